@@ -0,0 +1,535 @@
+use crate::format::{split_lines, Encoding, FileFormat};
+use crate::mapped::MappedFile;
+use ropey::{Rope, RopeBuilder};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::str;
+
+// The text of the open file, stored in ropes so that inserting and removing
+// lines anywhere in a large file stays cheap. Every row is terminated by a '\n',
+// so an empty buffer has no rows and a buffer holding "\n" has one empty row.
+// Positions within a row are byte offsets, matching the undo history.
+//
+// A huge file is instead served straight from a MappedFile. Editing it only
+// copies the rows that change into a rope, the rest are still read from the map.
+//
+// Line endings, the final newline and any BOM are not part of the text; they
+// are kept in format and restored when the buffer is written out.
+pub struct Buffer {
+    // The rows of the buffer in order
+    pieces: Vec<Piece>,
+    mapped: Option<MappedFile>,
+    format: FileFormat,
+}
+
+enum Piece {
+    // Rows start..end of the mapped file. The last piece of a file that is still
+    // being indexed has no end and grows as the indexer finds more rows.
+    Mapped { start: usize, end: Option<usize> },
+    // Rows held in memory
+    Text(Rope),
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            pieces: vec![Piece::Text(Rope::new())],
+            mapped: None,
+            format: FileFormat::new(),
+        }
+    }
+
+    pub fn map(file: &File) -> io::Result<Buffer> {
+        let mapped = MappedFile::open(file)?;
+        Ok(Buffer {
+            pieces: vec![Piece::Mapped {
+                start: 0,
+                end: None,
+            }],
+            format: mapped.format(),
+            mapped: Some(mapped),
+        })
+    }
+
+    // Decodes the contents of a file in encoding, or in the detected encoding if
+    // none is given, and remembers its line endings and BOM. Returns None if the
+    // contents are not valid in the given encoding.
+    pub fn from_bytes(bytes: &[u8], encoding: Option<Encoding>) -> Option<Buffer> {
+        let detected = encoding.unwrap_or_else(|| Encoding::detect(bytes));
+        let bom = !detected.bom().is_empty() && bytes.starts_with(detected.bom());
+        let text = match detected.decode(&bytes[if bom { detected.bom().len() } else { 0 }..]) {
+            Some(text) => text,
+            // A UTF-16 BOM followed by garbage; fall back to a byte encoding
+            None if encoding.is_none() => {
+                return Buffer::from_bytes(bytes, Some(Encoding::Windows1252))
+            }
+            None => return None,
+        };
+        let mut builder = RopeBuilder::new();
+        for line in split_lines(text.as_bytes()) {
+            builder.append(str::from_utf8(line).ok()?);
+            builder.append("\n");
+        }
+        Some(Buffer {
+            pieces: vec![Piece::Text(builder.finish())],
+            mapped: None,
+            format: FileFormat::detect(text.as_bytes(), detected, bom),
+        })
+    }
+
+    pub fn format(&self) -> FileFormat {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: FileFormat) {
+        self.format = format;
+    }
+
+    fn piece_len(&self, piece: &Piece) -> usize {
+        match piece {
+            Piece::Mapped { start, end } => {
+                let end = end.unwrap_or_else(|| self.mapped.as_ref().map_or(0, MappedFile::len));
+                end - start
+            }
+            Piece::Text(text) => text.len_lines() - 1,
+        }
+    }
+
+    // Number of rows. For a mapped file that is still being indexed, this is the
+    // number of rows found so far.
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(|piece| self.piece_len(piece)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Size of the text in bytes, as UTF-8 with '\n' line endings. Rows that are
+    // still mapped are counted as they are in the file.
+    pub fn len_bytes(&self) -> usize {
+        self.pieces
+            .iter()
+            .map(|piece| match (piece, &self.mapped) {
+                (Piece::Mapped { start, end }, Some(mapped)) => mapped.span(*start, *end),
+                (Piece::Mapped { .. }, None) => 0,
+                (Piece::Text(text), _) => text.len_bytes(),
+            })
+            .sum()
+    }
+
+    // Whether the buffer was loaded by mapping a large file
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.mapped
+            .as_ref()
+            .is_some_and(|mapped| !mapped.is_indexed())
+    }
+
+    // The piece holding row y and the index of the row within it
+    fn locate(&self, y: usize) -> (&Piece, usize) {
+        let mut row = 0;
+        for piece in self.pieces.iter() {
+            let len = self.piece_len(piece);
+            if y < row + len {
+                return (piece, y - row);
+            }
+            row += len;
+        }
+        panic!("row {} is past the end of the buffer", y);
+    }
+
+    // Text of row y without its line ending
+    pub fn line(&self, y: usize) -> String {
+        match self.locate(y) {
+            (Piece::Mapped { start, .. }, y) => self.mapped.as_ref().unwrap().line(start + y),
+            (Piece::Text(text), y) => {
+                let line = text.line(y);
+                let mut text = String::with_capacity(line.len_bytes());
+                for chunk in line.chunks() {
+                    text.push_str(chunk);
+                }
+                text.pop();
+                text
+            }
+        }
+    }
+
+    // Makes row y the first row of a piece, splitting the piece it is in, and
+    // returns the index of that piece
+    fn split_at(&mut self, y: usize) -> usize {
+        let mut row = 0;
+        for i in 0..self.pieces.len() {
+            if y == row {
+                return i;
+            }
+            let len = self.piece_len(&self.pieces[i]);
+            if y < row + len {
+                let at = y - row;
+                let rest = match &mut self.pieces[i] {
+                    Piece::Mapped { start, end } => {
+                        let rest = Piece::Mapped {
+                            start: *start + at,
+                            end: *end,
+                        };
+                        *end = Some(*start + at);
+                        rest
+                    }
+                    Piece::Text(text) => Piece::Text(text.split_off(text.line_to_char(at))),
+                };
+                self.pieces.insert(i + 1, rest);
+                return i + 1;
+            }
+            row += len;
+        }
+        self.pieces.len()
+    }
+
+    // Gathers rows y..y + count into one piece held in memory, copying those that
+    // are still mapped, and returns its rope with the index of row y in it
+    fn text_at(&mut self, y: usize, count: usize) -> (&mut Rope, usize) {
+        if self.pieces.len() == 1 && self.mapped.is_none() {
+            match &mut self.pieces[0] {
+                Piece::Text(text) => return (text, y),
+                Piece::Mapped { .. } => unreachable!(),
+            }
+        }
+        let mut first = self.split_at(y);
+        let mut last = self.split_at(y + count);
+        // Text on either side is merged in too, so that edits don't leave the
+        // buffer in ever smaller pieces
+        let mut index = 0;
+        if first > 0 {
+            if let Piece::Text(text) = &self.pieces[first - 1] {
+                first -= 1;
+                index = text.len_lines() - 1;
+            }
+        }
+        if let Some(Piece::Text(_)) = self.pieces.get(last) {
+            last += 1;
+        }
+        let mut gathered = Rope::new();
+        for piece in self.pieces.drain(first..last) {
+            match piece {
+                Piece::Text(text) => gathered.append(text),
+                Piece::Mapped { start, end } => {
+                    let mapped = self.mapped.as_ref().expect("Mapped rows without a map");
+                    let mut builder = RopeBuilder::new();
+                    for line in mapped.rows(start, end) {
+                        builder.append(&String::from_utf8_lossy(line));
+                        builder.append("\n");
+                    }
+                    gathered.append(builder.finish());
+                }
+            }
+        }
+        self.pieces.insert(first, Piece::Text(gathered));
+        match &mut self.pieces[first] {
+            Piece::Text(text) => (text, index),
+            Piece::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    pub fn insert(&mut self, y: usize, at: usize, text: &str) {
+        let (rope, y) = self.text_at(y, 1);
+        let index = rope.byte_to_char(rope.line_to_byte(y) + at);
+        rope.insert(index, text);
+    }
+
+    pub fn remove(&mut self, y: usize, start: usize, end: usize) {
+        let (rope, y) = self.text_at(y, 1);
+        let line = rope.line_to_byte(y);
+        let start = rope.byte_to_char(line + start);
+        let end = rope.byte_to_char(line + end);
+        rope.remove(start..end);
+    }
+
+    // Splits row y in two at byte offset at
+    pub fn split(&mut self, y: usize, at: usize) {
+        self.insert(y, at, "\n");
+    }
+
+    // Appends row y + 1 to row y
+    pub fn join(&mut self, y: usize) {
+        let (rope, y) = self.text_at(y, 2);
+        let end = rope.line_to_char(y + 1);
+        rope.remove(end - 1..end);
+    }
+
+    // Inserts an empty row at index y
+    pub fn insert_line(&mut self, y: usize) {
+        let (rope, y) = self.text_at(y, 0);
+        let index = rope.line_to_char(y);
+        rope.insert_char(index, '\n');
+    }
+
+    pub fn remove_line(&mut self, y: usize) {
+        let (rope, y) = self.text_at(y, 1);
+        let start = rope.line_to_char(y);
+        let end = rope.line_to_char(y + 1);
+        rope.remove(start..end);
+    }
+
+    // The contents of the buffer, used to fingerprint it for the undo history.
+    // Mapped rows are passed on without their line endings and BOM, the same as
+    // the text would be if the file had been read into a rope.
+    pub fn chunks(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        Box::new(self.pieces.iter().flat_map(move |piece| {
+            let chunks: Box<dyn Iterator<Item = &[u8]>> = match (piece, &self.mapped) {
+                (Piece::Mapped { start, end }, Some(mapped)) => Box::new(
+                    mapped
+                        .rows(*start, *end)
+                        .flat_map(|line| std::iter::once(line).chain(std::iter::once(&b"\n"[..]))),
+                ),
+                (Piece::Mapped { .. }, None) => Box::new(std::iter::empty()),
+                (Piece::Text(text), _) => Box::new(text.chunks().map(|chunk| chunk.as_bytes())),
+            };
+            chunks
+        }))
+    }
+
+    // Each row without its line ending. Rows that are still mapped are passed on
+    // as they are in the file, even if they are not valid UTF-8.
+    fn rows(&self) -> impl Iterator<Item = Cow<'_, [u8]>> {
+        self.pieces.iter().flat_map(move |piece| {
+            let rows: Box<dyn Iterator<Item = Cow<[u8]>>> = match (piece, &self.mapped) {
+                (Piece::Mapped { start, end }, Some(mapped)) => {
+                    Box::new(mapped.rows(*start, *end).map(Cow::Borrowed))
+                }
+                (Piece::Mapped { .. }, None) => Box::new(std::iter::empty()),
+                (Piece::Text(text), _) => {
+                    Box::new(text.lines().take(text.len_lines() - 1).map(|line| {
+                        let mut row = line.to_string();
+                        row.pop();
+                        Cow::Owned(row.into_bytes())
+                    }))
+                }
+            };
+            rows
+        })
+    }
+
+    // Once a mapped file is indexed, marks its format as mixing line endings if
+    // lines past the start it was detected from end differently. Returns true the
+    // one time that happens, so the editor can warn that saving converts them.
+    pub fn check_mixed_endings(&mut self) -> bool {
+        let found = self
+            .mapped
+            .as_ref()
+            .is_some_and(MappedFile::take_mixed_endings);
+        if found {
+            self.format.mixed_endings = true;
+        }
+        found
+    }
+
+    // Writes the contents as they will be stored on disk, in the buffer's format
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        if let Some(mapped) = &self.mapped {
+            // An unedited mapped file is written back byte for byte, unless its
+            // line endings have to be made the same
+            let unedited = matches!(
+                self.pieces[..],
+                [Piece::Mapped {
+                    start: 0,
+                    end: None
+                }]
+            );
+            if unedited && self.format == mapped.format() && !self.format.mixed_endings {
+                writer.write_all(mapped.bytes())?;
+                return writer.flush();
+            }
+        }
+        let encoding = self.format.encoding;
+        writer.write_all(self.format.bom_bytes())?;
+        let mut encoded = Vec::new();
+        let mut rows = self.rows().enumerate().peekable();
+        while let Some((y, row)) = rows.next() {
+            encoded.clear();
+            let mut result = match encoding {
+                Encoding::Utf8 => {
+                    encoded.extend_from_slice(&row);
+                    Ok(())
+                }
+                _ => encoding.encode(&String::from_utf8_lossy(&row), &mut encoded),
+            };
+            if rows.peek().is_some() || self.format.final_newline {
+                result = result
+                    .and_then(|_| encoding.encode(self.format.line_ending.as_str(), &mut encoded));
+            }
+            if let Err(c) = result {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "line {} has {:?}, which {} cannot encode",
+                        y + 1,
+                        c,
+                        encoding.name()
+                    ),
+                ));
+            }
+            writer.write_all(&encoded)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::LineEnding;
+    use crate::history::content_hash;
+
+    fn mapped(contents: &[u8]) -> Buffer {
+        let path = std::env::temp_dir().join(format!(
+            "vimacs-buffer-test-{}-{}",
+            std::process::id(),
+            content_hash(std::iter::once(contents))
+        ));
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        let buffer = Buffer::map(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        while buffer.is_indexing() {
+            std::thread::yield_now();
+        }
+        buffer
+    }
+
+    fn written(buffer: &Buffer) -> Vec<u8> {
+        let mut out = vec![];
+        buffer.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn rows(buffer: &Buffer) -> Vec<String> {
+        (0..buffer.len()).map(|y| buffer.line(y)).collect()
+    }
+
+    #[test]
+    fn edits_in_memory() {
+        let mut buffer = Buffer::from_bytes("one\ntwo\n漢字\n".as_bytes(), None).unwrap();
+        buffer.insert(0, 3, "!");
+        buffer.insert(2, 3, "x");
+        assert_eq!(rows(&buffer), vec!["one!", "two", "漢x字"]);
+        buffer.remove(2, 0, 4);
+        buffer.remove(0, 1, 3);
+        assert_eq!(rows(&buffer), vec!["o!", "two", "字"]);
+        buffer.split(1, 1);
+        assert_eq!(rows(&buffer), vec!["o!", "t", "wo", "字"]);
+        buffer.join(2);
+        buffer.join(0);
+        assert_eq!(rows(&buffer), vec!["o!t", "wo字"]);
+        buffer.insert_line(0);
+        buffer.insert_line(3);
+        assert_eq!(rows(&buffer), vec!["", "o!t", "wo字", ""]);
+        buffer.remove_line(1);
+        buffer.remove_line(2);
+        assert_eq!(rows(&buffer), vec!["", "wo字"]);
+        assert_eq!(written(&buffer), "\nwo字\n".as_bytes().to_vec());
+    }
+
+    #[test]
+    fn writes_back_in_the_format_it_read() {
+        let files: [&[u8]; 5] = [
+            b"one\r\ntwo\r\n",
+            b"one\ntwo",
+            b"one\r\ntwo",
+            b"\xef\xbb\xbfone\ntwo\n",
+            b"\xff\xfeo\0n\0e\0\r\0\n\0\x34\x6c\r\0\n\0",
+        ];
+        for contents in files.iter() {
+            let buffer = Buffer::from_bytes(contents, None).unwrap();
+            assert_eq!(written(&buffer), contents.to_vec());
+        }
+        let buffer = Buffer::from_bytes(files[4], None).unwrap();
+        assert!(buffer.format().encoding == Encoding::Utf16Le);
+        assert_eq!(rows(&buffer), vec!["one", "水"]);
+    }
+
+    #[test]
+    fn writes_in_a_changed_format() {
+        let mut buffer = Buffer::from_bytes(b"one\ntwo", None).unwrap();
+        let mut format = buffer.format();
+        format.line_ending = LineEnding::CrLf;
+        format.final_newline = true;
+        format.encoding = Encoding::Utf16Be;
+        format.bom = true;
+        buffer.set_format(format);
+        assert_eq!(
+            written(&buffer),
+            b"\xfe\xff\0o\0n\0e\0\r\0\n\0t\0w\0o\0\r\0\n".to_vec()
+        );
+
+        buffer.insert(1, 0, "漢");
+        format.encoding = Encoding::Latin1;
+        format.bom = false;
+        buffer.set_format(format);
+        let error = buffer.write_to(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "line 2 has '漢', which Latin-1 cannot encode"
+        );
+    }
+
+    #[test]
+    fn mapped_hashes_like_loaded() {
+        let contents = b"\xef\xbb\xbfone\r\ntwo\r\nthree\r\n";
+        let loaded = Buffer::from_bytes(contents, None).unwrap();
+        let mapped = mapped(contents);
+        assert_eq!(content_hash(mapped.chunks()), content_hash(loaded.chunks()));
+        assert_eq!(written(&mapped), contents.to_vec());
+    }
+
+    #[test]
+    fn mapped_finds_mixed_endings_past_the_start() {
+        let mut contents = b"one\r\n".repeat(20000);
+        contents.extend_from_slice(b"two\nthree\r\n");
+        let mut buffer = mapped(&contents);
+        assert!(!buffer.format().mixed_endings);
+        assert!(buffer.check_mixed_endings());
+        assert!(buffer.format().mixed_endings);
+        assert!(!buffer.check_mixed_endings());
+        let mut expected = b"one\r\n".repeat(20000);
+        expected.extend_from_slice(b"two\r\nthree\r\n");
+        assert_eq!(written(&buffer), expected);
+
+        // Mixed near the start, which opening the file already reported
+        let mut buffer = mapped(b"one\ntwo\r\n");
+        assert!(buffer.format().mixed_endings);
+        assert!(!buffer.check_mixed_endings());
+        let mut buffer = mapped(&b"one\r\n".repeat(20000));
+        assert!(!buffer.check_mixed_endings());
+    }
+
+    #[test]
+    fn mapped_edits_copy_only_edited_rows() {
+        let contents = b"one\r\ntwo\r\nthree\r\nfour";
+        let mut buffer = mapped(contents);
+        buffer.insert(1, 3, "!");
+        buffer.split(2, 2);
+        buffer.remove_line(0);
+        buffer.insert_line(4);
+        buffer.join(0);
+        let rows: Vec<String> = (0..buffer.len()).map(|y| buffer.line(y)).collect();
+        assert_eq!(rows, vec!["two!th", "ree", "four", ""]);
+        assert!(matches!(buffer.pieces.last(), Some(Piece::Text(_))));
+        assert!(matches!(buffer.pieces.first(), Some(Piece::Text(_))));
+        assert_eq!(written(&buffer), b"two!th\r\nree\r\nfour\r\n".to_vec());
+
+        let mut buffer = mapped(contents);
+        buffer.insert(1, 0, "2 ");
+        assert!(matches!(
+            buffer.pieces[..],
+            [Piece::Mapped { .. }, Piece::Text(_), Piece::Mapped { .. }]
+        ));
+        let mut loaded = Buffer::from_bytes(b"one\ntwo\nthree\nfour", None).unwrap();
+        loaded.insert(1, 0, "2 ");
+        assert_eq!(content_hash(buffer.chunks()), content_hash(loaded.chunks()));
+    }
+}
@@ -0,0 +1,13 @@
+// *** CONFIG ***
+// User settings live in $XDG_CONFIG_HOME/vimacs, or ~/.config/vimacs when that
+// is not set.
+use std::env;
+use std::path::PathBuf;
+
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("vimacs"))
+}
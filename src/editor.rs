@@ -1,128 +1,96 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{stdin, stdout, BufRead, BufReader, ErrorKind, Read, Write};
+use std::io::{self, stdin, stdout, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, SystemTime};
 
 extern crate termios;
-use crate::row::Row;
+use crate::buffer::Buffer;
+use crate::format::{Encoding, LineEnding};
+use crate::gutter::{Gutter, MarkerKind};
+use crate::history::{content_hash, Change, History};
+use crate::input::{Event, Input, Key, KeyCode, Modifiers, Mouse, MouseButton, MouseKind};
+use crate::row::{grapheme_width, Row, RowCache};
+use crate::rules::Open;
+use crate::save::{self, DiskState};
+use crate::screen::Screen;
+use crate::swap::SwapFile;
+use crate::syntax::{Highlight, Syntax};
+use crate::theme::{ColorDepth, Theme};
 use termios::Termios;
+use unicode_segmentation::UnicodeSegmentation;
 
 // *** Defines ***
-const ARROW_UP: u16 = 1000;
-const ARROW_LEFT: u16 = 1001;
-const ARROW_RIGHT: u16 = 1002;
-const ARROW_DOWN: u16 = 1003;
-const PAGE_UP: u16 = 1004;
-const PAGE_DOWN: u16 = 1005;
-const HOME_KEY: u16 = 1006;
-const END_KEY: u16 = 1007;
-const DELETE_KEY: u16 = 1008;
-const LEFT_BRACKET: u8 = 91;
-const ESCAPE: u8 = 27;
-const ESCAPE_U16: u16 = 27;
-const CTRL_Q: u16 = 17;
-const BACKSPACE: u16 = 127;
-const CTRL_H: u16 = 8;
-const RETURN: u16 = 13;
-const CTRL_L: u16 = 12;
-const CTRL_S: u16 = 19;
-const CTRL_F: u16 = 6;
 const QUIT_PRESSES: usize = 3;
+// Files at least this large are memory mapped instead of read up front
+const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// How long the user has to pause before the editor does background work, like
+// writing the swap file
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+// Lines highlighted between checks for input while idle
+const HIGHLIGHT_BATCH: usize = 256;
+// Most lines highlighted to find what a line starts in. When more lines above
+// it are dirty or were never highlighted, or the file is mapped, what it starts
+// in is guessed from this many lines above it instead.
+const LOOK_BACK: usize = 100;
+// Longest time unsaved changes go without being written to the swap file
+const SWAP_INTERVAL: Duration = Duration::from_secs(10);
+// Rows moved by one step of the mouse wheel
+const WHEEL_ROWS: usize = 3;
+// Drawn at the start of each screen line a wrapped row continues on, unless
+// VIMACS_SHOWBREAK sets another
+const DEFAULT_WRAP_MARKER: &str = "↪ ";
+// Keys shown in the message bar a page at a time, so that each fits on a line.
+// The first page is shown at startup and F1 shows the next.
+const HELP: [&str; 3] = [
+    "Help: Ctrl-S save | Ctrl-Q quit | Ctrl-F find | Ctrl-U/R undo/redo | F1 more",
+    "Ctrl-W wrap | Ctrl-N line numbers | Ctrl-T theme | Ctrl-Z suspend | F1 more",
+    "Ctrl-E line endings and BOM | Ctrl-O reopen in another encoding | F1 back",
+];
 const PROMPT_FORWARD: &str = "\x1b111";
 const PROMPT_BACKWARD: &str = "\x1b999";
 const PROMPT_DONE: &str = "\x1b000";
 pub const TAB_STOP: usize = 4;
-pub const HIGHLIGHT_NUMBERS: u32 = 1;
-pub const HIGHLIGHT_STRINGS: u32 = (1 << 1);
-pub const ALLOW_SINGLE_QUOTE: u32 = (1 << 2);
 
-fn is_control(c: char) -> bool {
-    (c as u8) < 32 || (c as u8) == 127
+// Set by the SIGWINCH handler, and cleared once the new size has been read
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
 }
 
-#[derive(Clone, PartialEq)]
-pub enum Highlight {
-    Normal,
-    Number,
-    Match,
-    Str,
-    Comment,
-    Keyword1,
-    Keyword2,
+// Set by the SIGCONT handler when the editor resumes after being stopped
+static CONTINUED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigcont(_: libc::c_int) {
+    CONTINUED.store(true, Ordering::Relaxed);
 }
-#[derive(Clone)]
-pub struct Syntax {
-    file_type: String,
-    file_match: Vec<String>,
-    single_line_comment_start: String,
-    flags: u32,
-    keywords: Vec<String>,
+
+// Leaves the alternate screen, turns off mouse and paste reporting and puts the
+// terminal back into the mode it was in before the editor started
+fn restore_terminal(fd: RawFd, orig_termios: &Termios) -> io::Result<()> {
+    let mut out = stdout();
+    out.write_all(b"\x1b[?2004l\x1b[?1006l\x1b[?1002l\x1b[?1000l\x1b[?25h\x1b[?1049l")?;
+    out.flush()?;
+    termios::tcsetattr(fd, termios::TCSAFLUSH, orig_termios)
 }
 
-impl Syntax {
-    fn new() -> Syntax {
-        Syntax {
-            file_type: String::new(),
-            file_match: vec![],
-            single_line_comment_start: String::new(),
-            flags: 0,
-            keywords: vec![],
-        }
-    }
-    pub fn get_flags(&self) -> u32 {
-        self.flags
-    }
-    pub fn c_hldb_extensions() -> Vec<String> {
-        vec![String::from(".c"), String::from(".h"), String::from(".cpp")]
-    }
-    pub fn hldb() -> Vec<Syntax> {
-        vec![Syntax {
-            file_type: String::from("c"),
-            file_match: Syntax::c_hldb_extensions(),
-            single_line_comment_start: String::from("//"),
-            flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS | ALLOW_SINGLE_QUOTE,
-            keywords: vec![
-                String::from("switch"),
-                String::from("if"),
-                String::from("while"),
-                String::from("for"),
-                String::from("break"),
-                String::from("continue"),
-                String::from("return"),
-                String::from("else"),
-                String::from("struct"),
-                String::from("union"),
-                String::from("typedef"),
-                String::from("static"),
-                String::from("enum"),
-                String::from("class"),
-                String::from("case"),
-                String::from("int|"),
-                String::from("long|"),
-                String::from("double|"),
-                String::from("float|"),
-                String::from("char|"),
-                String::from("unsigned|"),
-                String::from("signed|"),
-                String::from("void|"),
-            ],
-        }]
-    }
-    pub fn get_slcs(&self) -> &str {
-        &self.single_line_comment_start
-    }
-    pub fn slcs_len(&self) -> usize {
-        self.single_line_comment_start.len()
-    }
-    pub fn get_keywords(&self) -> &Vec<String> {
-        &self.keywords
-    }
+// Which of a wrapped row's screen lines display column rx is on
+fn segment_of(starts: &[usize], rx: usize) -> usize {
+    starts.iter().rposition(|start| *start <= rx).unwrap_or(0)
+}
+
+fn is_control(c: char) -> bool {
+    c.is_ascii_control()
 }
 
 pub struct Editor {
     orig_termios: Termios,
     stdin_fileno: RawFd,
+    input: Input,
     // stdout_fileno: RawFd,
     // Cursor x, cursor y
     cx: usize,
@@ -134,21 +102,57 @@ pub struct Editor {
     // tab_stop: usize,
     rx: usize,
     prev_cx: usize,
-    rows: Vec<Row>, // version: &'static str
+    buffer: Buffer,
+    // Rendered rows around the viewport, materialized from buffer on demand
+    rows: RowCache, // version: &'static str
     file_name: String,
     status_msg: String,
     msg_time: SystemTime,
     dirty: bool,
     quit_times: usize,
+    // The page of HELP that F1 shows next
+    help_page: usize,
     saved_cx: usize,
     saved_cy: usize,
     just_searched: bool,
     saved_highlight: Option<Vec<Highlight>>,
     syntax: Syntax,
+    // Every known syntax, the first one matching a file name is used
+    syntaxes: Vec<Syntax>,
+    // Syntax files that could not be loaded, with what is wrong with each
+    syntax_errors: Vec<String>,
+    history: History,
+    // None when another instance owns the swap file, or the buffer has no file
+    swap_path: Option<PathBuf>,
+    // Set when the buffer changed since the swap file was written
+    swap_pending: bool,
+    swap_time: SystemTime,
+    // The file on disk as of the last open or save
+    disk_state: Option<DiskState>,
+    // Where a mouse selection started, (cx, cy). The text between it and the
+    // cursor is selected.
+    selection: Option<(usize, usize)>,
+    // The last frame drawn, so that only changes are sent to the terminal
+    screen: Screen,
+    gutter: Gutter,
+    // Soft wrap shows long rows on several screen lines instead of scrolling
+    // sideways. wrap_offset counts the screen lines of the top row scrolled off
+    // the screen.
+    wrap: bool,
+    wrap_offset: usize,
+    wrap_marker: String,
+    theme: Theme,
+    color_depth: ColorDepth,
 }
 
 impl Drop for Editor {
     fn drop(&mut self) {
+        // The panic hook has already restored the terminal, and the swap file is
+        // kept so that the unsaved changes can be recovered
+        if std::thread::panicking() {
+            return;
+        }
+        self.remove_swap();
         self.clear_screen();
         self.disable_raw_mode();
     }
@@ -160,6 +164,7 @@ impl Editor {
         let mut editor = Editor {
             orig_termios: Termios::from_fd(stdin().as_raw_fd()).unwrap(),
             stdin_fileno: stdin().as_raw_fd(),
+            input: Input::new(stdin().as_raw_fd()),
             // stdout_fileno: stdout().as_raw_fd(),
             screen_rows: 0,
             screen_cols: 0,
@@ -167,38 +172,74 @@ impl Editor {
             cy: 0,
             row_offset: 0,
             col_offset: 0,
-            rows: Vec::new(),
+            buffer: Buffer::new(),
+            rows: RowCache::new(),
             // tab_stop: 4,
             rx: 0,
             prev_cx: 0,
             file_name: String::new(),
-            status_msg: String::from("Help: Ctrl-S = save | CTRL-q = quit | CTRL-f find"),
+            status_msg: String::from(HELP[0]),
             msg_time: SystemTime::now(),
             dirty: false,
             quit_times: 3,
+            help_page: 1,
             saved_cy: 0,
             saved_cx: 0,
             just_searched: false,
             saved_highlight: None,
-            syntax: Syntax::new()
+            syntax: Syntax::new(),
+            syntaxes: vec![],
+            syntax_errors: vec![],
+            history: History::new(),
+            swap_path: None,
+            swap_pending: false,
+            swap_time: SystemTime::now(),
+            disk_state: None,
+            selection: None,
+            screen: Screen::new(),
+            gutter: Gutter::new(),
+            wrap: false,
+            wrap_offset: 0,
+            wrap_marker: std::env::var("VIMACS_SHOWBREAK")
+                .unwrap_or_else(|_| String::from(DEFAULT_WRAP_MARKER)),
+            theme: Theme::initial(ColorDepth::detect()),
+            color_depth: ColorDepth::detect(),
             // file_type: String::from("c"),
             // file_match: vec![String::from("c"), String::from("h"), String::from("cpp")],
             // flags: HIGHLIGHT_NUMBERS
             // version: "0.0.1",
         };
-        editor.enable_raw_mode(false);
+        let (syntaxes, errors) = Syntax::load_all();
+        editor.syntaxes = syntaxes;
+        editor.syntax_errors = errors;
+        if !editor.syntax_errors.is_empty() {
+            editor.update_status(&editor.syntax_status(""));
+        }
+        editor.enable_raw_mode();
+        editor.install_panic_hook();
         editor.clear_screen();
         editor.get_window_size();
-        editor.screen_rows -= 2;
+        unsafe {
+            libc::signal(
+                libc::SIGWINCH,
+                handle_sigwinch as *const () as libc::sighandler_t,
+            );
+            libc::signal(
+                libc::SIGCONT,
+                handle_sigcont as *const () as libc::sighandler_t,
+            );
+        }
         editor
     }
 
     fn exit(&self) {
+        self.remove_swap();
         self.clear_screen();
         self.disable_raw_mode();
         std::process::exit(0);
     }
     fn exit_with_msg(&self, msg: &str) {
+        self.remove_swap();
         self.clear_screen();
         self.disable_raw_mode();
         println!("\r{}", msg);
@@ -206,14 +247,14 @@ impl Editor {
     }
     // *** Terminal ***
     fn disable_raw_mode(&self) {
-        termios::tcsetattr(self.stdin_fileno, termios::TCSAFLUSH, &self.orig_termios)
+        restore_terminal(self.stdin_fileno, &self.orig_termios)
             .expect("Error reverting terminal to original state");
     }
 
-    fn enable_raw_mode(&self, timeout: bool) {
+    fn enable_raw_mode(&self) {
         // get and current terminal flags
         use termios::*;
-        let mut raw = self.orig_termios.clone();
+        let mut raw = self.orig_termios;
         tcgetattr(self.stdin_fileno, &mut raw).expect("Error getting terminal attrs");
 
         // Configure flags for raw mode
@@ -221,170 +262,84 @@ impl Editor {
         raw.c_oflag &= !(OPOST);
         raw.c_cflag |= CS8;
         raw.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
-        // Set timeout on reads
-        if timeout {
-            raw.c_cc[VMIN] = 0;
-            raw.c_cc[VTIME] = 1;
-        }
+        // Reads block until a byte arrives, Input polls when it needs a timeout
+        raw.c_cc[VMIN] = 1;
+        raw.c_cc[VTIME] = 0;
 
         // Set flags and return
         tcsetattr(self.stdin_fileno, TCSAFLUSH, &raw).expect("Error setting terminal to raw mode");
+        // Draw on the alternate screen so the shell's scrollback is left alone.
+        // Report mouse presses, drags and the wheel as SGR sequences, and mark
+        // the start and end of pasted text.
+        stdout()
+            .write_all(b"\x1b[?1049h\x1b[?1000h\x1b[?1002h\x1b[?1006h\x1b[?2004h")
+            .unwrap();
+        stdout().flush().unwrap();
     }
 
-    fn read_key(&self) -> u16 {
-        // Buffer for next character
-        let mut next = [0; 1];
-        // let mut seq = [0; 3];
-        match stdin().read_exact(&mut next) {
-            Ok(_) => {
-                match next[0] {
-                    ESCAPE => {
-                        // enable read timeouts
-                        self.enable_raw_mode(true);
-                        // Buffer for escape sequence.
-                        let mut seq = [0; 3];
-                        match stdin().read(&mut seq) {
-                            Ok(_) => {
-                                // disable read timeouts
-                                self.enable_raw_mode(false);
-                                // if we get '[' at position zero it's a generated response
-                                if seq[0] == LEFT_BRACKET {
-                                    if seq[1] >= '0' as u8 && seq[1] <= '9' as u8 {
-                                        if seq[2] == '~' as u8
-                                        //|| seq[2] == 'C' as u8
-                                        {
-                                            match seq[1] {
-                                                1 => HOME_KEY,
-                                                3 => DELETE_KEY,
-                                                4 => END_KEY,
-                                                5 => PAGE_UP,
-                                                6 => PAGE_DOWN,
-                                                7 => HOME_KEY,
-                                                8 => END_KEY,
-                                                53 => PAGE_UP,
-                                                54 => PAGE_DOWN,
-                                                _ => ESCAPE as u16,
-                                            }
-                                        } else {
-                                            ESCAPE as u16
-                                        }
-                                    } else {
-                                        match seq[1] {
-                                            53 => PAGE_UP,
-                                            54 => PAGE_DOWN,
-                                            // A
-                                            65 => ARROW_UP,
-                                            // B
-                                            66 => ARROW_DOWN,
-                                            // C
-                                            67 => ARROW_RIGHT,
-                                            // D
-                                            68 => ARROW_LEFT,
-                                            // H
-                                            72 => HOME_KEY,
-                                            // F
-                                            70 => END_KEY,
-                                            // H
-                                            _ => ESCAPE as u16,
-                                        }
-                                    }
-                                } else if seq[0] == 'O' as u8 {
-                                    match seq[1] {
-                                        // H
-                                        72 => HOME_KEY,
-                                        // F
-                                        70 => END_KEY,
-                                        _ => ESCAPE as u16,
-                                    }
-                                } else if seq[0] == 'C' as u8 {
-                                    match seq[1] {
-                                        // A
-                                        65 => PAGE_UP,
-                                        // B
-                                        66 => PAGE_DOWN,
-                                        // C
-                                        67 => END_KEY,
-                                        // D
-                                        68 => HOME_KEY,
-                                        _ => ESCAPE as u16,
-                                    }
-                                } else {
-                                    ESCAPE as u16
-                                }
-                            }
-                            Err(e) => {
-                                // disable read timeouts
-                                self.enable_raw_mode(false);
-                                match e.kind() {
-                                    // If our read timed out, set c to zero
-                                    ErrorKind::UnexpectedEof => 0,
-                                    _ => panic!(e),
-                                }
-                            }
-                        }
-                    }
-                    _ => next[0] as u16,
-                }
-            }
-            Err(e) => match e.kind() {
-                // If our read timed out, set c to zero
-                ErrorKind::UnexpectedEof => 0,
-                _ => panic!(e),
-            },
+    // Restores the terminal before a panic message is printed, so that it is
+    // readable and the shell still works after a crash
+    fn install_panic_hook(&self) {
+        let fd = self.stdin_fileno;
+        let orig_termios = self.orig_termios;
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = restore_terminal(fd, &orig_termios);
+            default_hook(info);
+        }));
+    }
+
+    // Stops the editor and returns to the shell until it is resumed with fg.
+    // Raw mode turns Ctrl-Z into an ordinary key, so the signal is raised here.
+    fn suspend(&mut self) {
+        self.clear_screen();
+        self.disable_raw_mode();
+        unsafe {
+            libc::raise(libc::SIGTSTP);
         }
+        // Execution continues here after SIGCONT, refresh_screen restores the terminal
     }
+
     fn get_window_size(&mut self) {
-        // Move the cursor to bottom right corner of the screen
-        if stdout().write(b"\x1b[999C\x1b[999B").unwrap() != 12 {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let found = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        if found && size.ws_col != 0 {
+            self.screen_rows = size.ws_row as usize;
+            self.screen_cols = size.ws_col as usize;
+        } else if stdout().write(b"\x1b[999C\x1b[999B").unwrap() != 12 {
+            // Move the cursor to bottom right corner of the screen
             panic!("Unable to move to bottom right corner");
         } else {
             // Use the cursor's location to tell the size of the window
             self.get_cursor_position();
-            return;
         }
+        // Leave room for the status and message bars
+        self.screen_rows = std::cmp::max(self.screen_rows.saturating_sub(2), 1);
+        self.screen.resize(self.screen_rows + 2, self.screen_cols);
     }
     fn get_cursor_position(&mut self) {
-        let mut buf = [0; 32];
-        let mut rows: usize = 0;
-        let mut cols: usize = 0;
-        let mut index: usize = 0;
         // Send the command to get cursor position. We will be able to read the response at stdin
         if stdout().write(b"\x1b[6n").unwrap() != 4 {
             panic!("Failed at get cursor position in fallback method")
         }
         // Force flush so buffering doesn't delay our command
         stdout().flush().unwrap();
-        // Read the value returned by the terminal
-        stdin().read(&mut buf).unwrap();
-        // The buffer now contains "\x1b[" (chars 71, 91) at some index
-        // we want to find that index. The full response is "\x1b{rows};{cols}R"
-        for i in 0..buf.len() {
-            if buf[i] == ESCAPE && buf[i + 1] == LEFT_BRACKET {
-                index = i;
+        // Read the value returned by the terminal, "\x1b[{rows};{cols}R"
+        let mut response = vec![];
+        while let Some(byte) = self.input.read_byte(Some(Duration::from_secs(1))) {
+            if byte == b'R' {
                 break;
             }
+            response.push(byte);
         }
-        if buf[index] != ESCAPE || buf[index + 1] != LEFT_BRACKET {
-            panic!("Did not read cursor position!");
-        }
-        // After "\x1b[" is the row number followed by a semicolon (char 59)
-        index = index + 2;
-        while buf[index] != 59 {
-            // Convert the ascii row number to an integer
-            rows = rows * 10;
-            rows = rows + (buf[index] - 48) as usize;
-            index = index + 1;
-        }
-        // After the semicolon (char 59) is the col number followed by 'R' (char 82)
-        index = index + 1;
-        while buf[index] != 82 {
-            // Convert the ascii col number to an integer
-            cols = cols * 10;
-            cols = cols + (buf[index] - 48) as usize;
-            index = index + 1;
-        }
-        self.screen_rows = rows;
-        self.screen_cols = cols;
+        let start = match response.windows(2).position(|pair| pair == b"\x1b[") {
+            Some(start) => start + 2,
+            None => panic!("Did not read cursor position!"),
+        };
+        let position = String::from_utf8_lossy(&response[start..]).into_owned();
+        let mut numbers = position.split(';').map(|n| n.parse().unwrap_or(0));
+        self.screen_rows = numbers.next().unwrap_or(0);
+        self.screen_cols = numbers.next().unwrap_or(0);
     }
 
     // *** FILE I/O ***
@@ -392,105 +347,595 @@ impl Editor {
         self.file_name = String::from(file_name);
         self.select_syntax_highlight();
         // .expect(&format!("Could not open {0}", file_name));
-        if let Ok(file) = File::open(file_name) {
-            let buf_reader = BufReader::new(file);
-            for line in buf_reader.lines().map(|l| l.unwrap()) {
-                self.rows.push(Row::from(line, &mut self.syntax));
+        match File::open(file_name) {
+            Ok(file) => {
+                if let Err(msg) = self.load(file, None) {
+                    self.exit_with_msg(&msg);
+                }
+                self.check_swap();
+            }
+            Err(_) => {
+                self.exit_with_msg("No such file or directory. Use mkdir and touch to create it.")
             }
+        }
+    }
+
+    // Replaces the buffer with the contents of file, decoded in encoding or in
+    // the detected encoding if none is given
+    fn load(&mut self, mut file: File, encoding: Option<Encoding>) -> Result<(), String> {
+        self.disk_state = file.metadata().ok().map(|m| DiskState::from(&m));
+        let large = file.metadata().is_ok_and(|m| m.len() >= LARGE_FILE_SIZE);
+        // Mapping fails for files that aren't UTF-8, those are read whole
+        let mapped = if large && encoding.is_none() {
+            Buffer::map(&file).ok()
         } else {
-            self.exit_with_msg("No such file or directory. Use mkdir and touch to create it.");
+            None
+        };
+        self.buffer = match mapped {
+            Some(buffer) => buffer,
+            None => {
+                let mut bytes = vec![];
+                if let Err(e) = file.read_to_end(&mut bytes) {
+                    return Err(format!("Could not read {}: {}", self.file_name, e));
+                }
+                match Buffer::from_bytes(&bytes, encoding) {
+                    Some(buffer) => buffer,
+                    None => {
+                        return Err(format!(
+                            "{} is not valid {}",
+                            self.file_name,
+                            encoding.map_or("text", |e| e.name())
+                        ))
+                    }
+                }
+            }
+        };
+        self.rows.clear();
+        self.gutter.clear();
+        self.selection = None;
+        self.history = History::new();
+        // Hashing a huge file is slow, so only do it when there is history to check
+        let undo_path = self.sidecar_path("undo");
+        if undo_path.exists() {
+            let hash = content_hash(self.buffer.chunks());
+            self.history = History::load(&undo_path, hash).unwrap_or_else(History::new);
         }
+        if self.buffer.format().mixed_endings {
+            self.warn_mixed_endings();
+        }
+        Ok(())
+    }
+
+    fn warn_mixed_endings(&mut self) {
+        self.update_status(&format!(
+            "{} has both LF and CRLF line endings, saving makes them all {}",
+            self.file_name,
+            self.buffer.format().line_ending.name()
+        ));
     }
 
-    fn rows_to_string(&self) -> String {
-        let mut result = String::new();
-        for row in self.rows.iter() {
-            result.push_str(row.get_text());
-            result.push('\n');
+    // Reads the file again, decoding it in an encoding chosen by the user
+    fn reopen_with_encoding(&mut self) {
+        if self.file_name.is_empty() {
+            self.update_status("Nothing to reopen, the file has never been saved");
+            return;
+        }
+        if self.dirty {
+            self.update_status("Save or undo your changes before reopening the file");
+            return;
+        }
+        let names: Vec<&str> = Encoding::ALL.iter().map(|e| e.name()).collect();
+        let choice = self.prompt(
+            &format!("(ESC to cancel) Reopen as [{}]: ", names.join(", ")),
+            None,
+        );
+        if choice.is_empty() {
+            return;
+        }
+        let encoding = match Encoding::from_name(choice.trim()) {
+            Some(encoding) => encoding,
+            None => {
+                self.update_status(&format!("Unknown encoding: {}", choice));
+                return;
+            }
+        };
+        let loaded = File::open(&self.file_name)
+            .map_err(|e| format!("Could not open {}: {}", self.file_name, e))
+            .and_then(|file| self.load(file, Some(encoding)));
+        match loaded {
+            Ok(_) => {
+                self.swap_pending = true;
+                self.cx = 0;
+                self.cy = std::cmp::min(self.cy, self.buffer.len());
+                self.update_status(&format!("Reopened as {}", encoding.name()));
+            }
+            Err(msg) => self.update_status(&msg),
         }
-        result
     }
 
-    fn save(&mut self) {
-        if self.file_name.len() == 0 {
-            self.file_name = self.prompt("(ESC to cancel) Save as: ", None);
-            if self.file_name.len() == 0 {
-                self.update_status("Save Canceled");
+    // The undo history and swap file are kept in hidden sidecars beside the file,
+    // e.g. .main.rs.undo
+    fn sidecar_path(&self, extension: &str) -> PathBuf {
+        let file_path = Path::new(&self.file_name);
+        let name = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        file_path.with_file_name(format!(".{0}.{1}", name, extension))
+    }
+
+    // Looks for a swap file left by another instance or a crashed session, then
+    // claims the swap file for this one
+    fn check_swap(&mut self) {
+        let swap_path = self.sidecar_path("swp");
+        if let Some(swap) = SwapFile::read(&swap_path) {
+            if swap.is_owner_alive() {
+                let message = format!(
+                    "{} is already open in process {}. [o]pen anyway, [q]uit",
+                    self.file_name, swap.pid
+                );
+                if self.choose(&message, "oq") == 'q' {
+                    self.exit();
+                }
+                self.update_status("Opened without a swap file, changes can't be recovered");
                 return;
             }
+            if let Some(recovered) = self.recoverable(&swap) {
+                loop {
+                    let message = "Found unsaved changes from a crashed session. \
+                                   [r]ecover, [d]iff, [x] delete, [q]uit";
+                    match self.choose(message, "rdxq") {
+                        'r' => {
+                            self.recover(recovered, swap.cursor);
+                            break;
+                        }
+                        'd' => {
+                            let diffed = self.show_diff(Path::new(&self.file_name), &recovered);
+                            self.screen.invalidate();
+                            if let Err(msg) = diffed {
+                                self.update_status(&msg);
+                            }
+                        }
+                        'x' => break,
+                        _ => self.exit(),
+                    }
+                }
+            }
         }
-        self.select_syntax_highlight();
-        // This clone is unnecessary but keeps the borrow checker from complaining
-        let orig_name = self.file_name.clone();
-        let file_path = Path::new(&orig_name);
-        let old_extension = match file_path.extension() {
-            Some(ext) => match ext.to_str() {
-                Some(s) => s,
-                None => &"",
-            },
-            None => &"",
+        // Writing our own swap file replaces the old one
+        self.swap_path = Some(swap_path);
+        self.write_swap();
+    }
+    // Starts keeping a swap file unless another instance is editing the file and
+    // keeps one already
+    fn claim_swap(&mut self) {
+        let swap_path = self.sidecar_path("swp");
+        if SwapFile::read(&swap_path).is_some_and(|swap| swap.is_owner_alive()) {
+            return;
+        }
+        self.swap_path = Some(swap_path);
+        self.write_swap();
+    }
+
+    // The text saved in swap if it has changes newer than the file on disk
+    fn recoverable(&self, swap: &SwapFile) -> Option<Buffer> {
+        let text = swap.text.as_ref()?;
+        let file_modified = fs::metadata(&self.file_name).and_then(|m| m.modified());
+        if file_modified.is_ok_and(|modified| modified > swap.modified) {
+            return None;
+        }
+        let mut recovered = Buffer::from_bytes(text, Some(Encoding::Utf8))?;
+        recovered.set_format(self.buffer.format());
+        if content_hash(recovered.chunks()) == content_hash(self.buffer.chunks()) {
+            return None;
+        }
+        Some(recovered)
+    }
+
+    fn recover(&mut self, recovered: Buffer, cursor: (usize, usize)) {
+        self.buffer = recovered;
+        self.rows.clear();
+        // The undo history describes the file on disk, not the recovered text
+        self.history = History::new();
+        self.history.forget_saved();
+        self.dirty = true;
+        self.cy = std::cmp::min(cursor.1, self.buffer.len());
+        self.cx = 0;
+        if self.cy < self.buffer.len() {
+            self.cx = std::cmp::min(cursor.0, self.row(self.cy).len());
+        }
+        self.update_status("Recovered unsaved changes. Save to keep them.");
+    }
+
+    // Writes the swap file once the user pauses, or every SWAP_INTERVAL while they
+    // keep typing
+    fn update_swap(&mut self, idle: bool) {
+        let due = idle
+            || self
+                .swap_time
+                .elapsed()
+                .map_or(true, |t| t >= SWAP_INTERVAL);
+        if self.swap_pending && due {
+            self.write_swap();
+        }
+    }
+
+    fn write_swap(&mut self) {
+        let swap_path = match &self.swap_path {
+            Some(path) => path.clone(),
+            None => return,
         };
+        // Copying a huge file every few seconds would stall the editor, so only
+        // its lock is kept
+        let small = (self.buffer.len_bytes() as u64) < LARGE_FILE_SIZE;
+        let text = if self.dirty && small {
+            Some(&self.buffer)
+        } else {
+            None
+        };
+        if let Err(msg) = SwapFile::write(&swap_path, (self.cx, self.cy), text) {
+            self.update_status(&format!("Could not write swap file. {}", msg));
+        }
+        self.swap_pending = false;
+        self.swap_time = SystemTime::now();
+    }
+
+    fn remove_swap(&self) {
+        if let Some(swap_path) = &self.swap_path {
+            let _ = fs::remove_file(swap_path);
+        }
+    }
+
+    // Shows how text differs from the file at path in a pager, using the system's
+    // diff
+    // The pager takes over the terminal, so the screen has to be redrawn after
+    fn show_diff(&self, path: &Path, text: &Buffer) -> Result<(), String> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
         let temp_path =
-            file_path.with_file_name(file_path.with_extension(format!("{0}.lock", old_extension)));
-        let mut temp_file = match OpenOptions::new().write(true).create(true).open(&temp_path) {
-            Ok(f) => f,
-            Err(_) => {
-                self.update_status(&format!("Could not create lock file: {:?}", temp_path));
-                let _ = fs::remove_file(temp_path);
-                return;
+            std::env::temp_dir().join(format!("vimacs-{}-{}", std::process::id(), name));
+        let fail = |e: io::Error| format!("Could not write {}: {}", temp_path.display(), e);
+        // Never opened if it exists, it could be a link planted by another user
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .map_err(fail)?;
+        if let Err(e) = text.write_to(file) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(fail(e));
+        }
+        self.clear_screen();
+        self.disable_raw_mode();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg("diff -u -- \"$1\" \"$2\" | ${PAGER:-less}")
+            .arg("sh")
+            .arg(path)
+            .arg(&temp_path)
+            .status();
+        self.enable_raw_mode();
+        let _ = fs::remove_file(&temp_path);
+        status
+            .map(|_| ())
+            .map_err(|e| format!("Could not run diff: {}", e))
+    }
+
+    // Returns row y, rendering and highlighting it first if it isn't cached, or
+    // highlighting it again if it no longer starts in what it was highlighted with
+    fn row(&mut self, y: usize) -> &mut Row {
+        let start = self.open_before(y);
+        if self.rows.get(y).is_none() {
+            let row = Row::from(self.buffer.line(y), &self.syntax, start.clone());
+            self.rows.insert(y, row);
+        }
+        self.highlight_from(y, start);
+        self.rows.get_mut(y).unwrap()
+    }
+    // Whether what every line leaves open is remembered. That is only needed
+    // when a context can stay open past the end of a line, and going through a
+    // mapped file line by line would take as long as reading it whole.
+    fn tracks_open(&self) -> bool {
+        self.syntax.spans_lines() && !self.buffer.is_mapped()
+    }
+    // What line y starts in. The dirty lines above it and those never highlighted
+    // are highlighted first, without keeping their rows, unless there are too
+    // many of them.
+    fn open_before(&mut self, y: usize) -> Open {
+        if !self.tracks_open() {
+            return self.guess_open_before(y);
+        }
+        loop {
+            if let Some(open) = self.rows.open_before(y) {
+                return open;
+            }
+            if y - self.rows.next_to_highlight() > LOOK_BACK {
+                return self.guess_open_before(y);
             }
+            self.highlight_next();
+        }
+    }
+    // What line y starts in, taken from the cached row before it, or found by
+    // highlighting a few lines above it starting outside of any context. That is
+    // wrong when a comment or string opened further up is still open.
+    fn guess_open_before(&self, y: usize) -> Open {
+        if y == 0 {
+            return Open::top();
+        }
+        if !self.syntax.spans_lines() {
+            return Open::default();
+        }
+        if let Some(row) = self.rows.get(y - 1) {
+            return row.open();
+        }
+        if let Some(row) = self.rows.get(y) {
+            return row.start().clone();
+        }
+        let from = y.saturating_sub(LOOK_BACK);
+        let mut open = if from == 0 {
+            Open::top()
+        } else {
+            Open::default()
         };
-        match temp_file.write(self.rows_to_string().as_bytes()) {
-            Ok(_) => (),
-            Err(_) => {
-                self.update_status(&format!("Could not write to lock file: {:?}", temp_path));
-                let _ = fs::remove_file(&temp_path);
+        for i in from..y {
+            open = Row::from(self.buffer.line(i), &self.syntax, open).open();
+        }
+        open
+    }
+    // Finds what the first dirty or unknown line leaves open. Returns whether its
+    // row was cached and had to be highlighted again.
+    fn highlight_next(&mut self) -> bool {
+        let y = self.rows.next_to_highlight();
+        let start = self.rows.open_before(y).unwrap_or_default();
+        self.highlight_from(y, start)
+    }
+    // Highlights line y starting in start, in its row if that is cached, and
+    // records what the line leaves open unless start was guessed. Returns whether
+    // the cached row was highlighted again.
+    fn highlight_from(&mut self, y: usize, start: Open) -> bool {
+        let mut changed = false;
+        let end = match self.rows.get_mut(y) {
+            Some(row) => {
+                if *row.start() != start {
+                    row.update_highlight(&self.syntax, start);
+                    changed = true;
+                }
+                row.open()
+            }
+            None => Row::from(self.buffer.line(y), &self.syntax, start).open(),
+        };
+        if self.tracks_open() && y <= self.rows.next_to_highlight() {
+            self.rows.set_end(y, end);
+        }
+        changed
+    }
+    // Whether lines still have to be highlighted: those edits left dirty, and
+    // those down to the bottom of the screen, whose rows may have been drawn with
+    // a guess of what they start in
+    fn has_lines_to_highlight(&self) -> bool {
+        if !self.tracks_open() {
+            return false;
+        }
+        let bottom = std::cmp::min(self.row_offset + self.screen_rows, self.buffer.len());
+        self.rows.has_dirty() || self.rows.next_to_highlight() < bottom
+    }
+    // Highlights the lines left to highlight for as long as no key is waiting, a
+    // batch at a time, so scrolling down to them later is quick. Rows on screen
+    // that turn out to start in something else than was guessed are drawn again.
+    fn highlight_while_idle(&mut self) {
+        let mut redraw = false;
+        while self.has_lines_to_highlight()
+            && !self.input.wait(Duration::ZERO)
+            && !RESIZED.load(Ordering::Relaxed)
+        {
+            for _ in 0..HIGHLIGHT_BATCH {
+                if !self.has_lines_to_highlight() {
+                    break;
+                }
+                redraw |= self.highlight_next();
+            }
+        }
+        if redraw && !self.input.wait(Duration::ZERO) {
+            self.refresh_screen();
+        }
+    }
+    // Runs search on row y without adding it to the cache
+    fn search_row<F: Fn(&Row) -> Option<usize>>(&self, y: usize, search: F) -> Option<usize> {
+        match self.rows.get(y) {
+            Some(row) => search(row),
+            None => search(&Row::plain(self.buffer.line(y))),
+        }
+    }
+
+    fn save(&mut self) {
+        if self.file_name.is_empty() {
+            self.file_name = self.prompt("(ESC to cancel) Save as: ", None);
+            if self.file_name.is_empty() {
+                self.update_status("Save Canceled");
                 return;
             }
+        }
+        self.select_syntax_highlight();
+        if !self.check_disk(true) {
+            return;
+        }
+        let buffer = &self.buffer;
+        // A mapped buffer reads its rows from the file, which must not change under it
+        let saved =
+            save::write_atomically(Path::new(&self.file_name), !buffer.is_mapped(), |file| {
+                buffer.write_to(file)
+            });
+        match saved {
+            Ok(mut warning) => {
+                self.disk_state = DiskState::read(Path::new(&self.file_name));
+                let mut format = self.buffer.format();
+                format.mixed_endings = false;
+                self.buffer.set_format(format);
+                self.history.mark_saved();
+                self.gutter.unmark_all(MarkerKind::Changed);
+                self.dirty = false;
+                self.swap_pending = true;
+                // A buffer first saved with Save as had no swap file until now
+                if self.swap_path.is_none() {
+                    self.claim_swap();
+                }
+                if self
+                    .history
+                    .save(
+                        &self.sidecar_path("undo"),
+                        content_hash(self.buffer.chunks()),
+                    )
+                    .is_err()
+                {
+                    warning =
+                        warning.or_else(|| Some(String::from("could not write undo history")));
+                }
+                match warning {
+                    Some(warning) => self.update_status(&format!("Saved! ({})", warning)),
+                    None => self.update_status("Saved!"),
+                }
+            }
+            Err(msg) => self.update_status(&msg),
+        }
+    }
+    // Checks whether another program changed the file since it was opened or
+    // saved. An unmodified buffer is simply reloaded, otherwise the user picks
+    // which version to keep. Returns false if a save should not go ahead.
+    fn check_disk(&mut self, saving: bool) -> bool {
+        let path = PathBuf::from(&self.file_name);
+        // A file that was deleted is not a conflict, saving recreates it
+        let current = match (&self.disk_state, DiskState::read(&path)) {
+            (Some(recorded), Some(current)) if *recorded != current => current,
+            _ => return true,
         };
-        match fs::rename(&temp_path, file_path) {
+        if !self.dirty && !saving {
+            self.reload();
+            return true;
+        }
+        let mut message = format!(
+            "{} changed on disk. [r]eload, [o]verwrite, [d]iff",
+            self.file_name
+        );
+        let mut choices = String::from("rod");
+        if saving {
+            message.push_str(", [c]ancel");
+            choices.push('c');
+        }
+        loop {
+            match self.choose(&message, &choices) {
+                'r' => {
+                    self.reload();
+                    return false;
+                }
+                'o' => {
+                    self.disk_state = Some(current);
+                    if !saving {
+                        self.save();
+                    }
+                    return true;
+                }
+                'd' => {
+                    let diffed = self.show_diff(&path, &self.buffer);
+                    self.screen.invalidate();
+                    if let Err(msg) = diffed {
+                        self.update_status(&msg);
+                    }
+                }
+                _ => {
+                    self.update_status("Save Canceled");
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Replaces the buffer with the file as it is now on disk
+    fn reload(&mut self) {
+        let loaded = File::open(&self.file_name)
+            .map_err(|e| format!("Could not open {}: {}", self.file_name, e))
+            .and_then(|file| self.load(file, None));
+        match loaded {
             Ok(_) => {
-                let _ = fs::remove_file(temp_path);
-                self.update_status("Saved!");
                 self.dirty = false;
+                self.swap_pending = true;
+                self.cy = std::cmp::min(self.cy, self.buffer.len());
+                let row_len = if self.cy < self.buffer.len() {
+                    self.row(self.cy).len()
+                } else {
+                    0
+                };
+                self.cx = std::cmp::min(self.cx, row_len);
+                self.update_status(&format!("Reloaded {}, it changed on disk", self.file_name));
             }
-            Err(_) => {
-                self.update_status(&format!("Could save file: {:?}", file_path));
-                let _ = fs::remove_file(&temp_path);
+            Err(msg) => self.update_status(&msg),
+        }
+    }
+
+    // Converts the line endings, final newline or BOM written by the next save
+    fn convert_format(&mut self) {
+        let choice = self.prompt(
+            "(ESC to cancel) Convert to [lf, crlf, eol, noeol, bom, nobom]: ",
+            None,
+        );
+        let mut format = self.buffer.format();
+        match choice.trim().to_lowercase().as_str() {
+            "" => return,
+            "lf" => {
+                format.line_ending = LineEnding::Lf;
+                format.mixed_endings = false;
             }
-        };
+            "crlf" => {
+                format.line_ending = LineEnding::CrLf;
+                format.mixed_endings = false;
+            }
+            "eol" => format.final_newline = true,
+            "noeol" => format.final_newline = false,
+            "bom" => format.bom = true,
+            "nobom" => format.bom = false,
+            _ => {
+                self.update_status(&format!("Unknown format: {}", choice));
+                return;
+            }
+        }
+        self.buffer.set_format(format);
+        self.swap_pending = true;
+        self.history.forget_saved();
+        self.dirty = true;
+        self.update_status(&format!("File format is now {}", format.describe()));
     }
     // *** INPUT ***
 
     // Returns bool found
     fn find_prev(&mut self, query: &str) -> bool {
-        if self.rows.len() == 0 {
+        if self.buffer.is_empty() {
             return false;
         }
-        let mut cur_y = if self.cy < self.rows.len() {
+        let query_len = query.graphemes(true).count();
+        let mut cur_y = if self.cy < self.buffer.len() {
             self.cy
         } else {
-            self.rows.len() - 1
+            self.buffer.len() - 1
         };
         // Search current row behind cursor
-        if let Some(index) = self.rows[cur_y].search_reverse_to(self.cx, query) {
+        if let Some(index) = self.search_row(cur_y, |row| row.search_reverse_to(self.cx, query)) {
             self.cy = cur_y;
             self.cx = index;
-            self.saved_highlight = self.rows[cur_y].save_highlight(self.cx, query.len());
-            self.rows[cur_y].set_highlight_from(Highlight::Match, index, query.len());
+            self.highlight_match(cur_y, query_len);
             return true;
         };
-        for _ in 0..self.rows.len() {
+        for _ in 0..self.buffer.len() {
             if cur_y != 0 {
                 cur_y -= 1;
             } else {
-                cur_y = self.rows.len() - 1;
+                cur_y = self.buffer.len() - 1;
             }
-            if let Some(index) = self.rows[cur_y].search_reverse(query) {
+            if let Some(index) = self.search_row(cur_y, |row| row.search_reverse(query)) {
                 self.cy = cur_y;
                 self.cx = index;
-                self.saved_highlight = self.rows[cur_y].save_highlight(self.cx, query.len());
-                self.rows[cur_y].set_highlight_from(Highlight::Match, index, query.len());
+                self.highlight_match(cur_y, query_len);
                 return true;
             };
         }
@@ -499,35 +944,40 @@ impl Editor {
 
     // Returns bool found
     fn find_next(&mut self, query: &str) -> bool {
-        if self.rows.len() == 0 {
+        if self.buffer.is_empty() {
             return false;
         }
-        let mut cur_y = if self.cy >= self.rows.len() {
+        let query_len = query.graphemes(true).count();
+        let mut cur_y = if self.cy >= self.buffer.len() {
             0
         } else {
             self.cy
         };
-        if let Some(index) = self.rows[cur_y].search_from(self.cx, query) {
+        if let Some(index) = self.search_row(cur_y, |row| row.search_from(self.cx, query)) {
             self.cx = index;
-            self.saved_highlight = self.rows[cur_y].save_highlight(self.cx, query.len());
-            self.rows[cur_y].set_highlight_from(Highlight::Match, index, query.len());
+            self.highlight_match(cur_y, query_len);
             return true;
         };
-        for _ in 0..self.rows.len() {
+        for _ in 0..self.buffer.len() {
             cur_y += 1;
-            if cur_y >= self.rows.len() {
+            if cur_y >= self.buffer.len() {
                 cur_y = 0;
             }
-            if let Some(index) = self.rows[cur_y].search(query) {
+            if let Some(index) = self.search_row(cur_y, |row| row.search(query)) {
                 self.cx = index;
                 self.cy = cur_y;
-                self.saved_highlight = self.rows[cur_y].save_highlight(self.cx, query.len());
-                self.rows[cur_y].set_highlight_from(Highlight::Match, index, query.len());
+                self.highlight_match(cur_y, query_len);
                 return true;
             };
         }
         false
     }
+    // Highlights a match of len graphemes at the cursor in row y, saving the old highlight
+    fn highlight_match(&mut self, y: usize, len: usize) {
+        let cx = self.cx;
+        self.saved_highlight = self.row(y).save_highlight(cx, len);
+        self.row(y).set_highlight_from(Highlight::MATCH, cx, len);
+    }
     fn find(&mut self) {
         let mut query = String::new();
         self.saved_cx = self.cx;
@@ -540,11 +990,11 @@ impl Editor {
             } else {
                 query = self.prompt("(ESC to quit) No results for: ", Some(&query));
             }
-            if let Some(hl) = &self.saved_highlight {
-                self.rows[self.cy].set_highlight_group(self.cx, hl);
-                self.saved_highlight = None;
+            if let Some(hl) = self.saved_highlight.take() {
+                let cx = self.cx;
+                self.row(self.cy).set_highlight_group(cx, &hl);
             }
-            if query.len() == 0 {
+            if query.is_empty() {
                 return;
             } else if query.ends_with(PROMPT_FORWARD) {
                 query.truncate(query.len() - PROMPT_FORWARD.len());
@@ -568,60 +1018,303 @@ impl Editor {
                     self.saved_cy = self.cy;
                 }
             } else {
-                if self.cy < self.rows.len()
-                    && self.rows[self.cy].get(self.cx, self.cx + query.len()) == query
+                let query_len = query.graphemes(true).count();
+                let cx = self.cx;
+                if self.cy < self.buffer.len() && self.row(self.cy).get(cx, cx + query_len) == query
                 {
                     found = true;
-                    self.saved_highlight = self.rows[self.cy].save_highlight(self.cx, query.len());
-                    self.rows[self.cy].set_highlight_from(Highlight::Match, self.cx, query.len());
+                    self.highlight_match(self.cy, query_len);
                 } else {
                     found = self.find_next(&query);
                 }
             }
         }
     }
+    // Applies a single change to the rows and returns the cursor position (cx, cy)
+    // right after it
+    fn apply_change(&mut self, change: &Change) -> (usize, usize) {
+        self.swap_pending = true;
+        match change {
+            Change::Insert { y, at, text } => {
+                self.gutter.mark(MarkerKind::Changed, *y);
+                self.buffer.insert(*y, *at, text);
+                self.rows.changed(*y);
+                (self.row(*y).grapheme_index(at + text.len()), *y)
+            }
+            Change::Delete { y, at, text } => {
+                self.gutter.mark(MarkerKind::Changed, *y);
+                self.buffer.remove(*y, *at, at + text.len());
+                self.rows.changed(*y);
+                (self.row(*y).grapheme_index(*at), *y)
+            }
+            Change::Split { y, at } => {
+                self.gutter.insert_lines(y + 1, 1);
+                self.gutter.mark(MarkerKind::Changed, *y);
+                self.gutter.mark(MarkerKind::Changed, y + 1);
+                self.buffer.split(*y, *at);
+                self.rows.changed(*y);
+                self.rows.insert_line(y + 1);
+                (0, y + 1)
+            }
+            Change::Join { y, at } => {
+                self.gutter.remove_lines(y + 1, 1);
+                self.gutter.mark(MarkerKind::Changed, *y);
+                self.buffer.join(*y);
+                self.rows.remove_line(y + 1);
+                self.rows.changed(*y);
+                (self.row(*y).grapheme_index(*at), *y)
+            }
+            Change::InsertRow { y } => {
+                self.gutter.insert_lines(*y, 1);
+                self.gutter.mark(MarkerKind::Changed, *y);
+                self.buffer.insert_line(*y);
+                self.rows.insert_line(*y);
+                (0, *y)
+            }
+            Change::DeleteRow { y } => {
+                self.gutter.remove_lines(*y, 1);
+                self.buffer.remove_line(*y);
+                self.rows.remove_line(*y);
+                (0, *y)
+            }
+        }
+    }
+    // Applies the changes made by one editing command and records them in the undo history
+    fn edit(&mut self, changes: Vec<Change>, typing: bool) {
+        let cursor = (self.cx, self.cy);
+        for change in changes.iter() {
+            self.apply_change(change);
+        }
+        self.history.record(changes, cursor, typing);
+        self.dirty = !self.history.is_saved();
+    }
+    fn undo(&mut self) {
+        match self.history.undo() {
+            Some(step) => {
+                for change in step.changes.iter().rev() {
+                    self.apply_change(&change.inverse());
+                }
+                self.cx = step.cursor.0;
+                self.cy = step.cursor.1;
+                self.dirty = !self.history.is_saved();
+            }
+            None => self.update_status("Already at oldest change"),
+        }
+    }
+    fn redo(&mut self) {
+        match self.history.redo() {
+            Some(step) => {
+                for change in step.changes.iter() {
+                    let (cx, cy) = self.apply_change(change);
+                    self.cx = cx;
+                    self.cy = cy;
+                }
+                self.dirty = !self.history.is_saved();
+            }
+            None => self.update_status("Already at newest change"),
+        }
+    }
     fn insert_row(&mut self) {
-        if self.cy >= self.rows.len() {
-            self.rows.push(Row::new());
+        if self.cy >= self.buffer.len() {
+            self.edit(
+                vec![Change::InsertRow {
+                    y: self.buffer.len(),
+                }],
+                false,
+            );
             return;
         }
-        if self.cx < self.rows[self.cy].len() {
-            let next_row = self.rows[self.cy].split_off(self.cx, &mut self.syntax);
-            self.rows.insert(self.cy + 1, next_row);
+        let cx = self.cx;
+        let at = self.row(self.cy).byte_index(cx);
+        self.edit(vec![Change::Split { y: self.cy, at }], false);
+    }
+    // Leaves the cursor after the grapheme c ends up in. That is the one before
+    // the cursor when c is a combining mark or joiner that became part of it.
+    fn insert_char(&mut self, c: char) {
+        let mut changes = vec![];
+        let at = if self.cy < self.buffer.len() {
+            let cx = self.cx;
+            self.row(self.cy).byte_index(cx)
         } else {
-            self.rows.insert(self.cy + 1, Row::new());
-        }
+            changes.push(Change::InsertRow { y: self.cy });
+            0
+        };
+        changes.push(Change::Insert {
+            y: self.cy,
+            at,
+            text: c.to_string(),
+        });
+        self.edit(changes, true);
+        self.cx = self.row(self.cy).grapheme_index(at + c.len_utf8());
+        self.prev_cx = 0;
     }
-    fn insert_char(&mut self, c: u16) {
-        let new = char::from(c as u8);
-        if self.cy == self.rows.len() {
-            self.rows.push(Row::new());
+    // Inserts text at the cursor as a single undo step, leaving the cursor after it.
+    // Pasting over a selection replaces it in the same step.
+    fn paste(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
         }
-        self.rows[self.cy].insert(self.cx, new, &mut self.syntax);
-        self.dirty = true;
+        let (mut changes, (cx, mut y)) = self
+            .selection_changes()
+            .unwrap_or_else(|| (vec![], (self.cx, self.cy)));
+        self.selection = None;
+        // Deleting the selection leaves the text before its start as it is, so
+        // the position to insert at is the same before and after
+        let mut at = if y < self.buffer.len() {
+            self.row(y).byte_index(cx)
+        } else {
+            changes.push(Change::InsertRow { y });
+            0
+        };
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                changes.push(Change::Split { y, at });
+                y += 1;
+                at = 0;
+            }
+            if !line.is_empty() {
+                changes.push(Change::Insert {
+                    y,
+                    at,
+                    text: String::from(line),
+                });
+                at += line.len();
+            }
+        }
+        self.edit(changes, false);
+        self.cx = self.row(y).grapheme_index(at);
+        self.cy = y;
+        self.prev_cx = 0;
     }
     fn delete_row_char(&mut self, index: usize) {
-        if index >= self.rows[self.cy].len() {
+        let row = self.row(self.cy);
+        if index >= row.len() {
             return;
         }
-        self.dirty = true;
-        self.rows[self.cy].remove(index, &mut self.syntax);
+        let at = row.byte_index(index);
+        let text = String::from(&row.get_text()[at..row.byte_index(index + 1)]);
+        self.edit(
+            vec![Change::Delete {
+                y: self.cy,
+                at,
+                text,
+            }],
+            false,
+        );
     }
 
     fn delete_char(&mut self) {
-        if self.cy >= self.rows.len() {
+        if self.cy >= self.buffer.len() {
             return;
         };
         if self.cx > 0 {
             self.delete_row_char(self.cx - 1);
             self.cx -= 1;
         } else if self.cy > 0 {
-            let delete_row = self.rows.remove(self.cy);
-            self.cx = self.rows[self.cy - 1].len();
-            self.rows[self.cy - 1].join(&delete_row);
+            let cx = self.row(self.cy - 1).len();
+            let at = self.row(self.cy - 1).get_text().len();
+            self.edit(vec![Change::Join { y: self.cy - 1, at }], false);
+            self.cx = cx;
             self.cy -= 1;
         }
     }
+    // The selected text as (start, end) positions, each (cx, cy) and clamped to
+    // the buffer, or None if nothing is selected
+    fn selected_range(&mut self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.selection?;
+        let (start, end) = if (anchor.1, anchor.0) < (self.cy, self.cx) {
+            (anchor, (self.cx, self.cy))
+        } else {
+            ((self.cx, self.cy), anchor)
+        };
+        let start = self.clamp_position(start);
+        let end = self.clamp_position(end);
+        if start == end {
+            None
+        } else {
+            Some((start, end))
+        }
+    }
+    fn clamp_position(&mut self, (x, y): (usize, usize)) -> (usize, usize) {
+        if self.buffer.is_empty() {
+            return (0, 0);
+        }
+        if y >= self.buffer.len() {
+            let y = self.buffer.len() - 1;
+            return (self.row(y).len(), y);
+        }
+        (std::cmp::min(x, self.row(y).len()), y)
+    }
+    // Deletes the selected text as a single undo step
+    fn delete_selection(&mut self) {
+        let (changes, (sx, sy)) = match self.selection_changes() {
+            Some(deletion) => deletion,
+            None => return,
+        };
+        self.selection = None;
+        self.edit(changes, false);
+        self.cx = sx;
+        self.cy = sy;
+    }
+    // The changes that delete the selected text and the position it starts at,
+    // or None if nothing is selected
+    fn selection_changes(&mut self) -> Option<(Vec<Change>, (usize, usize))> {
+        let ((sx, sy), (ex, ey)) = self.selected_range()?;
+        let at = self.row(sy).byte_index(sx);
+        let mut changes = vec![];
+        if sy == ey {
+            let end = self.row(sy).byte_index(ex);
+            let text = String::from(&self.row(sy).get_text()[at..end]);
+            changes.push(Change::Delete { y: sy, at, text });
+        } else {
+            let text = String::from(&self.row(sy).get_text()[at..]);
+            changes.push(Change::Delete { y: sy, at, text });
+            // Each following row is joined onto the start row and its selected
+            // part deleted, so the row to join is always sy + 1
+            for y in sy + 1..=ey {
+                let end = if y == ey {
+                    self.row(y).byte_index(ex)
+                } else {
+                    self.row(y).get_text().len()
+                };
+                let text = String::from(&self.row(y).get_text()[..end]);
+                changes.push(Change::Join { y: sy, at });
+                changes.push(Change::Delete { y: sy, at, text });
+            }
+        }
+        changes.retain(|change| !matches!(change, Change::Delete { text, .. } if text.is_empty()));
+        Some((changes, (sx, sy)))
+    }
+    fn goto_line(&mut self) {
+        let input = self.prompt("Go to line: ", None);
+        if input.is_empty() {
+            return;
+        }
+        match input.trim().parse::<usize>() {
+            Ok(line) if line > 0 => {
+                self.cy = std::cmp::min(line - 1, self.buffer.len());
+                self.cx = 0;
+                self.prev_cx = 0;
+            }
+            _ => self.update_status(&format!("Not a line number: {}", input)),
+        }
+    }
+    // Shows message until the user presses one of the keys in choices
+    fn choose(&mut self, message: &str, choices: &str) -> char {
+        loop {
+            self.update_status(message);
+            self.refresh_screen();
+            let c = self
+                .input
+                .read_key()
+                .and_then(|key| key.typed_char())
+                .map(|c| c.to_ascii_lowercase());
+            if let Some(c) = c.filter(|c| choices.contains(*c)) {
+                self.update_status("");
+                return c;
+            }
+        }
+    }
     // This has two modes - Normal, when no previous prompt is passed
     // And interactive, when the caller passes in a previous prompt
     // In interactive mode, a value is returned after every key press
@@ -633,83 +1326,126 @@ impl Editor {
         loop {
             self.update_status(&format!("{0}{1}", prompt, input));
             self.refresh_screen();
-            let c = self.read_key();
-            if c == ESCAPE_U16 {
-                self.update_status("");
-                return String::new();
-            } else if c == DELETE_KEY || c == CTRL_H || c == BACKSPACE {
-                input.pop();
-                if prev_input != None && input.len() > 0 {
+            let key = match self.input.read_event() {
+                Some(Event::Key(key)) => key,
+                // A prompt holds a single line, so only the first line of a paste
+                Some(Event::Paste(text)) => {
+                    let line = text.lines().next().unwrap_or("");
+                    input.extend(line.chars().filter(|c| !c.is_control()));
+                    if prev_input.is_some() && !input.is_empty() {
+                        return input;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            match (key.code, key.modifiers) {
+                (KeyCode::Escape, _) => {
+                    self.update_status("");
+                    return String::new();
+                }
+                (KeyCode::Delete, _)
+                | (KeyCode::Backspace, _)
+                | (KeyCode::Char('h'), Modifiers::CTRL) => {
+                    input.pop();
+                    if prev_input.is_some() && !input.is_empty() {
+                        return input;
+                    }
+                }
+                (KeyCode::Enter, _) if !input.is_empty() => {
+                    self.update_status("");
+                    if prev_input.is_some() {
+                        input.push_str(PROMPT_DONE);
+                    }
                     return input;
                 }
-            } else if input.len() > 0 && c == RETURN {
-                self.update_status("");
-                if prev_input != None {
-                    input.push_str(PROMPT_DONE);
-                }
-                return input;
-            } else if c > 32 && c < 127 {
-                // If c is a printable ascii character
-                input.push(char::from(c as u8));
-                if prev_input != None {
+                (KeyCode::Left, _) | (KeyCode::Up, _) if !input.is_empty() => {
+                    self.update_status("");
+                    if prev_input.is_some() {
+                        input.push_str(PROMPT_BACKWARD);
+                    }
                     return input;
                 }
-            } else if input.len() > 0 && (c == ARROW_LEFT || c == ARROW_UP) {
-                self.update_status("");
-                if prev_input != None {
-                    input.push_str(PROMPT_BACKWARD);
+                (KeyCode::Right, _) | (KeyCode::Down, _) if !input.is_empty() => {
+                    self.update_status("");
+                    if prev_input.is_some() {
+                        input.push_str(PROMPT_FORWARD);
+                    }
+                    return input;
                 }
-                return input;
-            } else if input.len() > 0 && (c == ARROW_RIGHT || c == ARROW_DOWN) {
-                self.update_status("");
-                if prev_input != None {
-                    input.push_str(PROMPT_FORWARD);
+                _ => {
+                    // If key is a printable character
+                    if let Some(c) = key.typed_char().filter(|c| *c != '\t') {
+                        input.push(c);
+                        if prev_input.is_some() {
+                            return input;
+                        }
+                    }
                 }
-                return input;
             }
         }
     }
     fn process_keypress(&mut self) {
-        let c = self.read_key();
-        if c == 0 {
-            return;
-        }
+        let event = match self.input.read_event() {
+            Some(event) => event,
+            None => return,
+        };
         self.update_status("");
-
-        match c {
-            // Nothing - do nothing
-            RETURN => {
+        match event {
+            Event::Key(key) => self.process_key(key),
+            Event::Mouse(mouse) => self.process_mouse(mouse),
+            Event::Paste(text) => self.paste(&text),
+        }
+    }
+    fn process_key(&mut self, key: Key) {
+        // Any key ends a selection, and deleting removes the selected text
+        if self.selection.is_some() {
+            match (key.code, key.modifiers) {
+                (KeyCode::Backspace, _)
+                | (KeyCode::Delete, _)
+                | (KeyCode::Char('h'), Modifiers::CTRL) => {
+                    self.delete_selection();
+                    self.quit_times = QUIT_PRESSES;
+                    return;
+                }
+                _ => self.selection = None,
+            }
+        }
+        match (key.code, key.modifiers) {
+            (KeyCode::Enter, _) => {
                 self.insert_row();
-                self.move_cursor(ARROW_DOWN);
+                self.move_cursor(KeyCode::Down);
             }
-            ARROW_UP | ARROW_DOWN | ARROW_LEFT | ARROW_RIGHT => self.move_cursor(c),
-            PAGE_DOWN => {
+            (KeyCode::Up, _) | (KeyCode::Down, _) | (KeyCode::Left, _) | (KeyCode::Right, _) => {
+                self.move_cursor(key.code)
+            }
+            (KeyCode::PageDown, _) => {
                 self.cy = self.row_offset + self.screen_rows - 1;
                 for _ in 0..self.screen_rows - 1 {
-                    self.move_cursor(ARROW_DOWN)
+                    self.move_cursor(KeyCode::Down)
                 }
             }
-            PAGE_UP => {
+            (KeyCode::PageUp, _) => {
                 self.cy = self.row_offset;
                 for _ in 0..self.screen_rows - 1 {
-                    self.move_cursor(ARROW_UP)
+                    self.move_cursor(KeyCode::Up)
                 }
             }
-            HOME_KEY => {
+            (KeyCode::Home, _) => {
                 self.cx = 0;
             }
-            END_KEY => {
-                if self.cy < self.rows.len() {
-                    self.cx = self.rows[self.cy].len()
+            (KeyCode::End, _) => {
+                if self.cy < self.buffer.len() {
+                    self.cx = self.row(self.cy).len()
                 }
             }
-            BACKSPACE | CTRL_H => self.delete_char(),
-            DELETE_KEY => {
-                self.move_cursor(ARROW_RIGHT);
+            (KeyCode::Backspace, _) | (KeyCode::Char('h'), Modifiers::CTRL) => self.delete_char(),
+            (KeyCode::Delete, _) => {
+                self.move_cursor(KeyCode::Right);
                 self.delete_char();
             }
-            CTRL_S => self.save(),
-            CTRL_Q => {
+            (KeyCode::Char('s'), Modifiers::CTRL) => self.save(),
+            (KeyCode::Char('q'), Modifiers::CTRL) => {
                 self.quit_times -= 1;
                 if self.quit_times > 0 && self.dirty {
                     self.update_status(&format!(
@@ -720,55 +1456,140 @@ impl Editor {
                 }
                 self.exit()
             }
-            CTRL_L => (),
-            CTRL_F => self.find(),
-            ESCAPE_U16 => {
+            // Redraw everything, in case something else wrote to the terminal
+            (KeyCode::Char('l'), Modifiers::CTRL) => self.screen.invalidate(),
+            (KeyCode::Char('f'), Modifiers::CTRL) => self.find(),
+            (KeyCode::Char('u'), Modifiers::CTRL) => self.undo(),
+            (KeyCode::Char('r'), Modifiers::CTRL) => self.redo(),
+            (KeyCode::Char('e'), Modifiers::CTRL) => self.convert_format(),
+            (KeyCode::Char('o'), Modifiers::CTRL) => self.reopen_with_encoding(),
+            (KeyCode::Char('z'), Modifiers::CTRL) => self.suspend(),
+            (KeyCode::Char('w'), Modifiers::CTRL) => self.toggle_wrap(),
+            (KeyCode::Char('n'), Modifiers::CTRL) => {
+                self.gutter.numbers = self.gutter.numbers.next();
+                self.update_status(&format!("Line numbers: {}", self.gutter.numbers.name()));
+            }
+            (KeyCode::Char('t'), Modifiers::CTRL) => self.switch_theme(),
+            (KeyCode::F(1), _) => {
+                self.update_status(HELP[self.help_page]);
+                self.help_page = (self.help_page + 1) % HELP.len();
+            }
+            (KeyCode::Escape, _) => {
                 if self.just_searched {
                     self.just_searched = false;
                     self.cx = self.saved_cx;
                     self.cy = self.saved_cy;
                 }
             }
-            // 9 | 32..=126 => {
-            0..=126 => {
-                self.insert_char(c);
-                self.move_cursor(ARROW_RIGHT)
+            _ => {
+                if let Some(c) = key.typed_char() {
+                    self.insert_char(c);
+                }
             }
-            _ => (),
         }
         self.quit_times = QUIT_PRESSES;
     }
 
-    fn move_cursor(&mut self, c: u16) {
-        let row_exists = self.cy < self.rows.len();
+    fn process_mouse(&mut self, mouse: Mouse) {
+        match mouse.kind {
+            MouseKind::Press(MouseButton::Left) if mouse.row < self.screen_rows => {
+                self.move_cursor_to(mouse.column, mouse.row);
+                self.selection = Some((self.cx, self.cy));
+            }
+            // The status bar shows the cursor line, clicking it jumps to another
+            MouseKind::Press(MouseButton::Left) if mouse.row == self.screen_rows => {
+                self.selection = None;
+                self.goto_line();
+            }
+            // Dragging past the bottom of the text scrolls down through scroll()
+            MouseKind::Drag(MouseButton::Left) if self.selection.is_some() => {
+                self.move_cursor_to(mouse.column, mouse.row);
+            }
+            // A click without a drag selects nothing
+            MouseKind::Release(MouseButton::Left) if self.selection == Some((self.cx, self.cy)) => {
+                self.selection = None;
+            }
+            MouseKind::ScrollUp => {
+                self.row_offset = self.row_offset.saturating_sub(WHEEL_ROWS);
+                self.wrap_offset = 0;
+                self.keep_cursor_in_view();
+            }
+            MouseKind::ScrollDown => {
+                let last = (self.buffer.len() + 1).saturating_sub(self.screen_rows);
+                self.row_offset = std::cmp::min(self.row_offset + WHEEL_ROWS, last);
+                self.wrap_offset = 0;
+                self.keep_cursor_in_view();
+            }
+            _ => (),
+        }
+    }
+    // Places the cursor on the text drawn at a screen cell
+    fn move_cursor_to(&mut self, column: usize, row: usize) {
+        let (y, segment) = self.screen_line(row);
+        self.cy = std::cmp::min(y, self.buffer.len());
+        self.prev_cx = 0;
+        self.cx = 0;
+        if self.cy < self.buffer.len() {
+            let x = column.saturating_sub(self.gutter_width());
+            self.cx = if self.wrap {
+                let x = x.saturating_sub(self.indent(segment));
+                self.cx_at(y, segment, x)
+            } else {
+                let rx = self.col_offset + x;
+                self.row(y).rx_to_cx(rx)
+            };
+        }
+    }
+    // Moves the cursor onto the screen after scrolling, otherwise scroll() would
+    // scroll back to it
+    fn keep_cursor_in_view(&mut self) {
+        let last = self.screen_line(self.screen_rows - 1).0;
+        self.cy = self.cy.clamp(self.row_offset, last);
+        self.cy = std::cmp::min(self.cy, self.buffer.len());
+        let len = if self.cy < self.buffer.len() {
+            let cy = self.cy;
+            self.row(cy).len()
+        } else {
+            0
+        };
+        self.cx = std::cmp::min(self.cx, len);
+    }
+
+    fn move_cursor(&mut self, direction: KeyCode) {
+        let row_exists = self.cy < self.buffer.len();
         let row_size = if row_exists {
-            self.rows[self.cy].len()
+            self.row(self.cy).len()
         } else {
             0
         };
-        match c {
-            ARROW_UP => {
+        match direction {
+            // Wrapped rows are moved through a screen line at a time
+            KeyCode::Up | KeyCode::Down if self.wrap => {
+                self.move_visual(direction == KeyCode::Down);
+                return;
+            }
+            KeyCode::Up => {
                 if self.cy != 0 {
                     self.cy -= 1
                 }
                 self.cx = std::cmp::max(self.cx, self.prev_cx);
             }
-            ARROW_DOWN => {
-                if self.cy < self.rows.len() {
+            KeyCode::Down => {
+                if self.cy < self.buffer.len() {
                     self.cy += 1
                 }
                 self.cx = std::cmp::max(self.cx, self.prev_cx);
             }
-            ARROW_LEFT => {
+            KeyCode::Left => {
                 self.prev_cx = 0;
                 if self.cx > 0 {
                     self.cx -= 1;
                 } else if self.cy > 0 {
                     self.cy -= 1;
-                    self.cx = self.rows[self.cy].len();
+                    self.cx = self.row(self.cy).len();
                 }
             }
-            ARROW_RIGHT => {
+            KeyCode::Right => {
                 self.prev_cx = 0;
                 if self.cx < row_size {
                     self.cx += 1
@@ -779,9 +1600,9 @@ impl Editor {
             }
             _ => (),
         }
-        let new_row_exists = self.cy < self.rows.len();
+        let new_row_exists = self.cy < self.buffer.len();
         let new_row_len = if new_row_exists {
-            self.rows[self.cy].len()
+            self.row(self.cy).len()
         } else {
             0
         };
@@ -794,45 +1615,54 @@ impl Editor {
     pub fn run(&mut self) {
         // self.refresh_screen();
         loop {
+            self.check_disk(false);
+            // A large file may only turn out to mix line endings once it is indexed
+            if self.buffer.check_mixed_endings() {
+                self.warn_mixed_endings();
+            }
             self.refresh_screen();
-            self.process_keypress();
+            self.highlight_while_idle();
+            if self.input.wait(IDLE_TIMEOUT) {
+                self.process_keypress();
+                self.update_swap(false);
+            } else {
+                self.update_swap(true);
+            }
         }
     }
 
     // *** OUTPUT ***
-    fn draw_status_bar(&self, output: &mut String) {
-        // Invert Colors
-        output.push_str("\x1b[7m");
-        let mut status: String = if self.file_name.len() == 0 {
-            format!("[No Name] - {0} lines ", self.rows.len())
-        } else if self.file_name.len() <= 20 {
-            format!("{0} - {1} lines ", self.file_name, self.rows.len())
+    fn draw_status_bar(&mut self) {
+        // Still indexing a large file, more lines may follow
+        let more = if self.buffer.is_indexing() { "+" } else { "" };
+        let mut status: String = if self.file_name.is_empty() {
+            format!("[No Name] - {0}{1} lines ", self.buffer.len(), more)
         } else {
-            format!("{0} - {1} lines ", &self.file_name[..20], self.rows.len())
+            let name: String = self.file_name.chars().take(20).collect();
+            format!("{0} - {1}{2} lines ", name, self.buffer.len(), more)
         };
         if self.dirty {
             status.push_str(" (modified) ");
         }
-        if self.syntax.file_type.len() == 0 {
+        if self.syntax.file_type.is_empty() {
             status.push_str("no ft");
         } else {
             status.push_str(&self.syntax.file_type);
         }
-        let row_position = format!("{0}/{1}", self.cy + 1, self.rows.len());
-        while status.len() < self.screen_cols {
-            if self.screen_cols - status.len() == row_position.len() {
+        status.push_str(&format!(" | {} ", self.buffer.format().describe()));
+        let row_position = format!("{0}/{1}", self.cy + 1, self.buffer.len());
+        let mut width = status.chars().count();
+        while width < self.screen_cols {
+            if self.screen_cols - width == row_position.len() {
                 status.push_str(&row_position);
                 break;
             }
             status.push(' ');
+            width += 1;
         }
-        if status.len() > self.screen_cols {
-            status.truncate(self.screen_cols);
-        }
-        output.push_str(&status);
-
-        // Turn off formatting changes from above
-        output.push_str("\x1b[m\r\n");
+        // Invert Colors
+        self.screen
+            .put_str(0, self.screen_rows, &status, self.theme.style("ui.statusbar"));
         // output.push_str(&format!(
         //     "\x1b[K   Cx: {0}, Rx: {1}, Col_Offset: {2}, Render at: {3}",
         //     self.cx,
@@ -846,162 +1676,394 @@ impl Editor {
         self.msg_time = SystemTime::now();
     }
 
-    fn draw_message_bar(&mut self, output: &mut String) {
-        output.push_str("\x1b[K");
+    fn draw_message_bar(&mut self) {
         if self.msg_time.elapsed().unwrap() > Duration::from_secs(5) {
             return;
         }
-        if self.status_msg.len() > self.screen_cols {
-            output.push_str(&self.status_msg[..self.screen_cols]);
-        } else {
-            output.push_str(&self.status_msg);
-        }
+        let style = self.theme.style("ui.message");
+        self.screen
+            .put_str(0, self.screen_rows + 1, &self.status_msg, style);
     }
 
     fn refresh_screen(&mut self) {
+        if CONTINUED.swap(false, Ordering::Relaxed) {
+            // The shell resets the terminal while the editor is stopped, and the
+            // window may have been resized in the meantime
+            self.enable_raw_mode();
+            RESIZED.store(true, Ordering::Relaxed);
+        }
+        if RESIZED.swap(false, Ordering::Relaxed) {
+            // Resizing the screen makes the next frame start from a blank terminal,
+            // since terminals reflow or keep stale text on resize
+            self.get_window_size();
+        }
         self.scroll();
-        let mut output = String::new();
-        // Hide cursor, Move to top left
-        output.push_str("\x1b[?25l\x1b[H");
-        // Clear rows and push new contents to output string
-        self.draw_rows(&mut output);
-        self.draw_status_bar(&mut output);
-        self.draw_message_bar(&mut output);
-        let cursor_position = format!(
-            "\x1b[{0};{1}H",
-            (self.cy - self.row_offset) + 1,
-            (self.rx - self.col_offset) + 2
-        );
-        // Move cursor to top left and show
-        output.push_str(&cursor_position);
-        output.push_str("\x1b[?25h");
-        // Write all commands to stdout at once
-        stdout().write(output.as_bytes()).unwrap();
+        self.screen.clear(self.theme.style("normal"));
+        self.draw_rows();
+        self.draw_status_bar();
+        self.draw_message_bar();
+        let (x, y) = if self.wrap {
+            self.wrapped_cursor()
+        } else {
+            (self.rx - self.col_offset, self.cy - self.row_offset)
+        };
+        let cursor = (self.gutter_width() + x, y);
+        // Write only what changed since the last frame, all at once
+        let output = self.screen.render(cursor);
+        stdout().write_all(output.as_bytes()).unwrap();
         stdout().flush().unwrap();
     }
     fn scroll(&mut self) {
         self.rx = 0;
-        if self.cy < self.rows.len() {
-            self.rx = self.rows[self.cy].cx_to_rx(self.cx);
+        if self.cy < self.buffer.len() {
+            let cx = self.cx;
+            self.rx = self.row(self.cy).cx_to_rx(cx);
         }
-        if self.cy < self.row_offset {
-            self.row_offset = self.cy;
-        }
-        if self.cy >= self.row_offset + self.screen_rows {
-            self.row_offset = self.cy - self.screen_rows + 1;
-        }
-        if self.rx < self.col_offset {
-            self.col_offset = self.rx;
+        if self.wrap {
+            self.scroll_wrapped();
+        } else {
+            // After the window grows, pull the view back so it doesn't end in blank
+            // rows past the end of the file. The checks below then keep the cursor in
+            // view if it shrank.
+            self.row_offset = std::cmp::min(
+                self.row_offset,
+                (self.buffer.len() + 1).saturating_sub(self.screen_rows),
+            );
+            if self.cy < self.row_offset {
+                self.row_offset = self.cy;
+            }
+            if self.cy >= self.row_offset + self.screen_rows {
+                self.row_offset = self.cy - self.screen_rows + 1;
+            }
+            if self.rx < self.col_offset {
+                self.col_offset = self.rx;
+            }
+            let text_cols = self.text_cols();
+            if self.rx >= self.col_offset + text_cols {
+                self.col_offset = self.rx - text_cols + 1
+            }
         }
-        if self.rx >= self.col_offset + self.screen_cols {
-            self.col_offset = self.rx - self.screen_cols + 1
+        // Only keep the visible rows and the cursor row rendered
+        let (start, cy) = (self.row_offset, self.cy);
+        let end = std::cmp::min(start + self.screen_rows, self.buffer.len());
+        self.rows.retain(|y| (start..end).contains(&y) || y == cy);
+        for y in start..end {
+            self.row(y);
         }
     }
-    fn draw_rows(&self, output: &mut String) {
+    // Width of the gutter left of the text
+    fn gutter_width(&self) -> usize {
+        self.gutter.width(self.buffer.len())
+    }
+    // Columns left for the text beside the gutter
+    fn text_cols(&self) -> usize {
+        std::cmp::max(self.screen_cols.saturating_sub(self.gutter_width()), 1)
+    }
+    fn draw_rows(&mut self) {
+        let selected = self.selected_range();
+        let gutter_width = self.gutter_width();
+        let text_cols = self.text_cols();
+        let marker = self.wrap_marker.clone();
         let welcome_msg = concat!("ViMacs Editor -- Version ", env!("CARGO_PKG_VERSION"));
+        let (mut y, mut segment) = (self.row_offset, self.wrap_offset);
+        // Screen line starts of row y, worked out once for all its lines
+        let mut starts = vec![];
         for i in 0..self.screen_rows {
-            let current_row = i + self.row_offset;
-            if current_row >= self.rows.len() {
-                if self.rows.len() == 0 && i == self.screen_rows / 4 {
-                    if welcome_msg.len() > self.screen_cols {
-                        output.push_str(&welcome_msg[0..self.screen_cols]);
-                    } else {
-                        let padding = (self.screen_cols - welcome_msg.len()) / 2;
-                        if padding > 0 {
-                            output.push('~');
-                        }
-                        for _ in 0..padding - 1 {
-                            output.push(' ');
-                        }
-                        output.push_str(&welcome_msg);
-                    }
-                } else {
-                    // Write a tilde
-                    output.push('~');
+            if y >= self.buffer.len() {
+                self.gutter
+                    .draw(&mut self.screen, &self.theme, i, None, self.cy, gutter_width);
+                if self.buffer.is_empty() && i == self.screen_rows / 4 {
+                    let padding = text_cols.saturating_sub(welcome_msg.len()) / 2;
+                    let style = self.theme.style("normal");
+                    self.screen
+                        .put_str(gutter_width + padding, i, welcome_msg, style);
                 }
+                y += 1;
+                continue;
+            }
+            if starts.is_empty() {
+                starts = self.wrap_starts(y);
+            }
+            segment = std::cmp::min(segment, starts.len() - 1);
+            // Display columns of the row shown on this screen line
+            let (from, to, x) = if !self.wrap {
+                (self.col_offset, self.col_offset + text_cols, gutter_width)
+            } else if segment == 0 {
+                (
+                    0,
+                    starts.get(1).copied().unwrap_or(usize::MAX),
+                    gutter_width,
+                )
             } else {
-                output.push('~');
-                let rendered_row = &self.rows[current_row].get_render();
-                let mut previous_highlight = &Highlight::Normal;
-                for (i, c) in self.rows[current_row]
-                    .get_render_slice(self.col_offset, self.col_offset + self.screen_cols)
-                    .chars()
-                    .enumerate()
-                {
-                    if i > rendered_row.len() {
-                        break;
-                    }
-                    if is_control(c) {
-                        output.push_str("\x1b[7m");
-                        if (c as u8) <= 26 {
-                            let sym = ((c as u8) + 64) as char;
-                            output.push(sym);
-                        } else {
-                            output.push('?');
-                        }
-                        output.push_str("\x1b[m");
-                        output.push_str(&format!(
-                            "\x1b[{0}m",
-                            self.syntax_to_color(previous_highlight)
-                        ));
-                    }
-                    let current_highlight = self.rows[current_row].get_highlight_at(i);
-                    if current_highlight != previous_highlight {
-                        previous_highlight = current_highlight;
-                        output.push_str(&self.index_to_color_string(&self.rows[current_row], i));
-                    }
-                    output.push(c);
+                let style = self.theme.style("ui.wrap");
+                let x = self.screen.put_str(gutter_width, i, &marker, style);
+                (
+                    starts[segment],
+                    starts.get(segment + 1).copied().unwrap_or(usize::MAX),
+                    x,
+                )
+            };
+            if segment == 0 {
+                self.gutter
+                    .draw(&mut self.screen, &self.theme, i, Some(y), self.cy, gutter_width);
+            }
+            self.draw_text(i, y, (from, to), x, selected);
+            segment += 1;
+            if segment == starts.len() {
+                y += 1;
+                segment = 0;
+                starts.clear();
+            }
+        }
+    }
+    // Draws display columns from..to of row y at column x of screen row i
+    fn draw_text(
+        &mut self,
+        i: usize,
+        y: usize,
+        (from, to): (usize, usize),
+        x: usize,
+        selected: Option<((usize, usize), (usize, usize))>,
+    ) {
+        let row = match self.rows.get(y) {
+            Some(row) => row,
+            None => return,
+        };
+        // Display columns of the selected part of this row
+        let (select_from, select_to) = match selected {
+            Some(((sx, sy), (ex, ey))) if (sy..=ey).contains(&y) => (
+                if y == sy { row.cx_to_rx(sx) } else { 0 },
+                if y == ey {
+                    row.cx_to_rx(ex)
+                } else {
+                    usize::MAX
+                },
+            ),
+            _ => (0, 0),
+        };
+        // Display column and char index into the rendered row of the current grapheme
+        let mut col = 0;
+        let mut index = 0;
+        for g in row.get_render().graphemes(true) {
+            let width = grapheme_width(g);
+            let start = col;
+            let highlight = row.get_highlight_at(index);
+            col += width;
+            index += g.chars().count();
+            if col <= from {
+                continue;
+            }
+            if start < from {
+                // A wide grapheme cut off by the left edge of the screen
+                for offset in 0..col - from {
+                    self.screen.put(x + offset, i, " ", self.theme.style("normal"));
                 }
-                output.push_str("\x1b[39m");
+                continue;
+            }
+            if col > to {
+                break;
+            }
+            let c = g.chars().next().unwrap_or(' ');
+            let (text, mut style) = if is_control(c) {
+                let sym = if (c as u8) <= 26 {
+                    ((c as u8) + 64) as char
+                } else {
+                    '?'
+                };
+                (sym.to_string(), self.theme.style("ui.control"))
+            } else {
+                (String::from(g), self.theme.style(highlight.group()))
+            };
+            if (select_from..select_to).contains(&start) {
+                style = self.theme.over(style, "ui.selection");
             }
-            //clear the rest of the line, then return and newline
-            output.push_str("\x1b[K\r\n");
+            self.screen.put(x + start - from, i, &text, style);
         }
     }
-    fn index_to_color_string(&self, row: &Row, i: usize) -> String {
-        let color = self.syntax_to_color(row.get_highlight_at(i));
-        format!("\x1b[{}m", color)
+    // *** SOFT WRAP ***
+    // Display columns where each screen line of row y starts. Without soft wrap
+    // every row takes a single screen line.
+    fn wrap_starts(&mut self, y: usize) -> Vec<usize> {
+        if !self.wrap || y >= self.buffer.len() {
+            return vec![0];
+        }
+        let width = self.text_cols();
+        let rest = std::cmp::max(width.saturating_sub(self.marker_width()), 1);
+        self.row(y).wrap(width, rest)
+    }
+    fn marker_width(&self) -> usize {
+        self.wrap_marker.graphemes(true).map(grapheme_width).sum()
+    }
+    // Columns taken by the continuation marker on a screen line of a row
+    fn indent(&self, segment: usize) -> usize {
+        if segment > 0 {
+            self.marker_width()
+        } else {
+            0
+        }
+    }
+    // The row and which of its screen lines is drawn on screen row i
+    fn screen_line(&mut self, i: usize) -> (usize, usize) {
+        let (mut y, mut segment) = (self.row_offset, self.wrap_offset);
+        for _ in 0..i {
+            segment += 1;
+            if segment >= self.wrap_starts(y).len() {
+                y += 1;
+                segment = 0;
+            }
+        }
+        (y, segment)
+    }
+    // The cursor's column in the text area and its row on the screen
+    fn wrapped_cursor(&mut self) -> (usize, usize) {
+        let starts = self.wrap_starts(self.cy);
+        let segment = segment_of(&starts, self.rx);
+        let mut line = segment;
+        for y in self.row_offset..self.cy {
+            line += self.wrap_starts(y).len();
+        }
+        let x = self.indent(segment) + self.rx - starts[segment];
+        (x, line.saturating_sub(self.wrap_offset))
+    }
+    // Index of the grapheme x columns into a screen line of row y, or of the
+    // last one on that line when x is past its end
+    fn cx_at(&mut self, y: usize, segment: usize, x: usize) -> usize {
+        let starts = self.wrap_starts(y);
+        let rx = starts[segment] + x;
+        let row = self.row(y);
+        match starts.get(segment + 1) {
+            Some(next) if rx >= *next => row.rx_to_cx(next - 1),
+            _ => row.rx_to_cx(rx),
+        }
+    }
+    // Moves the cursor up or down a screen line, keeping its column in the text
+    // so that continuation markers don't push it sideways
+    fn move_visual(&mut self, down: bool) {
+        let len = self.buffer.len();
+        let rx = if self.cy < len {
+            let cx = self.cx;
+            self.row(self.cy).cx_to_rx(cx)
+        } else {
+            0
+        };
+        let starts = self.wrap_starts(self.cy);
+        let segment = segment_of(&starts, rx);
+        let x = rx - starts[segment];
+        let (y, segment) = if down {
+            if segment + 1 < starts.len() {
+                (self.cy, segment + 1)
+            } else if self.cy < len {
+                (self.cy + 1, 0)
+            } else {
+                return;
+            }
+        } else if segment > 0 {
+            (self.cy, segment - 1)
+        } else if self.cy > 0 {
+            (self.cy - 1, self.wrap_starts(self.cy - 1).len() - 1)
+        } else {
+            return;
+        };
+        self.cy = y;
+        self.cx = if y < len {
+            self.cx_at(y, segment, x)
+        } else {
+            0
+        };
+        self.prev_cx = 0;
+    }
+    fn scroll_wrapped(&mut self) {
+        self.col_offset = 0;
+        let height = self.wrap_starts(self.row_offset).len();
+        self.wrap_offset = std::cmp::min(self.wrap_offset, height - 1);
+        let starts = self.wrap_starts(self.cy);
+        let segment = segment_of(&starts, self.rx);
+        if (self.cy, segment) < (self.row_offset, self.wrap_offset) {
+            self.row_offset = self.cy;
+            self.wrap_offset = segment;
+            return;
+        }
+        // Every row takes at least one screen line
+        if self.cy >= self.row_offset + self.screen_rows {
+            self.row_offset = self.cy + 1 - self.screen_rows;
+            self.wrap_offset = 0;
+        }
+        // Scroll off the top as many screen lines as the cursor is below the
+        // bottom, a row at a time
+        let mut line = self.wrapped_cursor().1;
+        while line >= self.screen_rows {
+            let height = self.wrap_starts(self.row_offset).len();
+            let scrolled = std::cmp::min(line + 1 - self.screen_rows, height - self.wrap_offset);
+            self.wrap_offset += scrolled;
+            line -= scrolled;
+            if self.wrap_offset == height {
+                self.row_offset += 1;
+                self.wrap_offset = 0;
+            }
+        }
+    }
+    fn toggle_wrap(&mut self) {
+        self.wrap = !self.wrap;
+        self.col_offset = 0;
+        self.wrap_offset = 0;
+        let state = if self.wrap { "on" } else { "off" };
+        self.update_status(&format!("Soft wrap: {}", state));
     }
-    fn syntax_to_color(&self, highlight: &Highlight) -> u8 {
-        match highlight {
-            Highlight::Number => 31,
-            Highlight::Normal => 39,
-            Highlight::Match => 34,
-            Highlight::Comment => 36,
-            Highlight::Str => 35,
-            Highlight::Keyword1 => 33,
-            Highlight::Keyword2 => 32,
+    // *** THEMES ***
+    fn switch_theme(&mut self) {
+        let names = Theme::available();
+        let choice = self.prompt(
+            &format!("(ESC to cancel) Theme [{}]: ", names.join(", ")),
+            None,
+        );
+        if choice.is_empty() {
+            return;
+        }
+        match Theme::load(choice.trim(), self.color_depth) {
+            Ok(theme) => {
+                self.theme = theme;
+                self.update_status(&format!("Theme: {}", self.theme.name));
+            }
+            Err(msg) => self.update_status(&msg),
         }
     }
     fn select_syntax_highlight(&mut self) {
-        let last_period = self.file_name.rfind('.');
-        for entry in Syntax::hldb() {
-            for m in &entry.file_match {
-                let is_ext = &m[0..1] == ".";
-                if is_ext && last_period != None && self.file_name.ends_with(m) {
-                    self.syntax = entry.clone();
-                    self.update_syntax();
-                    self.update_status(&format!("Updated syntax for {0}", self.syntax.file_type));
-                    return;
-                } else if !is_ext {
-                    if let Some(_) = self.file_name.find(m) {
-                        self.syntax = entry;
-                        self.update_syntax();
-                        return;
-                    }
+        match self.syntaxes.iter().find(|s| s.matches(&self.file_name)) {
+            Some(syntax) => {
+                self.syntax = syntax.clone();
+                self.update_syntax();
+                let msg = format!("Updated syntax for {0}", self.syntax.file_type);
+                self.update_status(&self.syntax_status(&msg));
+            }
+            None => {
+                self.syntax = Syntax::new();
+                self.update_syntax();
+                if !self.syntax_errors.is_empty() {
+                    self.update_status(&self.syntax_status(""));
                 }
             }
         }
-        self.syntax = Syntax::new();
-        self.update_syntax();
     }
-    fn update_syntax(&mut self) {
-        for row in self.rows.iter_mut() {
-            row.update_highlight(&mut self.syntax);
+    // msg followed by the syntax files that could not be loaded, so that this
+    // message doesn't hide them
+    fn syntax_status(&self, msg: &str) -> String {
+        if self.syntax_errors.is_empty() {
+            return String::from(msg);
+        }
+        let errors = format!("Could not load syntax {}", self.syntax_errors.join("; "));
+        if msg.is_empty() {
+            errors
+        } else {
+            format!("{}. {}", msg, errors)
         }
     }
+    // Drops every row, the rows on screen are highlighted with the new syntax
+    // when they are drawn
+    fn update_syntax(&mut self) {
+        self.rows.clear();
+    }
     fn clear_screen(&self) {
         // Clear screen, move cursor to top left
-        stdout().write(b"\x1b[2J\x1b[H").unwrap();
+        stdout().write_all(b"\x1b[2J\x1b[H").unwrap();
     }
 }
@@ -0,0 +1,334 @@
+// *** FILE FORMAT ***
+// The buffer always holds UTF-8 text with rows separated by '\n'. These settings
+// record how the file on disk actually looked so that saving writes it back the
+// same way.
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+
+// Characters for bytes 0x80 to 0x9f in Windows-1252. The five bytes it leaves
+// undefined map to the C1 control with the same value, so every byte decodes.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 5] = [
+        Encoding::Utf8,
+        Encoding::Utf16Le,
+        Encoding::Utf16Be,
+        Encoding::Latin1,
+        Encoding::Windows1252,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Windows1252 => "Windows-1252",
+        }
+    }
+
+    // Accepts the names shown in the status bar, ignoring case and dashes
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        let wanted: String = name
+            .chars()
+            .filter(|c| *c != '-' && *c != '_')
+            .collect::<String>()
+            .to_lowercase();
+        Encoding::ALL.iter().copied().find(|encoding| {
+            encoding.name().replace('-', "").to_lowercase() == wanted
+                || (*encoding == Encoding::Latin1 && wanted == "iso88591")
+                || (*encoding == Encoding::Windows1252 && wanted == "cp1252")
+        })
+    }
+
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => UTF8_BOM,
+            Encoding::Utf16Le => UTF16LE_BOM,
+            Encoding::Utf16Be => UTF16BE_BOM,
+            Encoding::Latin1 | Encoding::Windows1252 => b"",
+        }
+    }
+
+    // Picks the encoding of a file from its BOM. Without one the file is UTF-8
+    // if it decodes as such, and Windows-1252 otherwise since that accepts any
+    // byte sequence.
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be].iter() {
+            if bytes.starts_with(encoding.bom()) {
+                return *encoding;
+            }
+        }
+        if std::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Windows1252
+        }
+    }
+
+    // Like detect, for the start of a file too large to read whole, which may
+    // stop partway through a character
+    pub fn detect_prefix(bytes: &[u8]) -> Encoding {
+        match std::str::from_utf8(bytes) {
+            Err(e) if e.error_len().is_none() => Encoding::detect(&bytes[..e.valid_up_to()]),
+            _ => Encoding::detect(bytes),
+        }
+    }
+
+    // Returns None if bytes are not valid in this encoding
+    pub fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => std::str::from_utf8(bytes).ok().map(String::from),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if bytes.len() % 2 != 0 {
+                    return None;
+                }
+                let units = bytes.chunks(2).map(|pair| {
+                    if self == Encoding::Utf16Le {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    }
+                });
+                std::char::decode_utf16(units)
+                    .collect::<Result<_, _>>()
+                    .ok()
+            }
+            Encoding::Latin1 => Some(bytes.iter().map(|b| *b as char).collect()),
+            Encoding::Windows1252 => Some(
+                bytes
+                    .iter()
+                    .map(|b| match b {
+                        0x80..=0x9f => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                        _ => *b as char,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // Appends text to out, or returns the first character this encoding cannot hold
+    pub fn encode(self, text: &str, out: &mut Vec<u8>) -> Result<(), char> {
+        match self {
+            Encoding::Utf8 => out.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            Encoding::Utf16Be => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_be_bytes());
+                }
+            }
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    if c as u32 > 0xff {
+                        return Err(c);
+                    }
+                    out.push(c as u8);
+                }
+            }
+            Encoding::Windows1252 => {
+                for c in text.chars() {
+                    let byte = match WINDOWS_1252_HIGH.iter().position(|high| *high == c) {
+                        Some(i) => 0x80 + i as u8,
+                        None if (c as u32) < 0x80 || (0xa0..=0xff).contains(&(c as u32)) => c as u8,
+                        None => return Err(c),
+                    };
+                    out.push(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    // Whether the last row is followed by a line ending
+    pub final_newline: bool,
+    pub bom: bool,
+    // Whether some lines end differently from the first. Saving gives them all
+    // line_ending.
+    pub mixed_endings: bool,
+}
+
+impl FileFormat {
+    // The format for new files
+    pub fn new() -> FileFormat {
+        FileFormat {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            bom: false,
+            mixed_endings: false,
+        }
+    }
+
+    // Detects the line endings of decoded text. The line ending style is taken
+    // from the first line of the file.
+    pub fn detect(text: &[u8], encoding: Encoding, bom: bool) -> FileFormat {
+        let mut endings = text
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .map(|(i, _)| i > 0 && text[i - 1] == b'\r');
+        let crlf = endings.next().unwrap_or(false);
+        FileFormat {
+            encoding,
+            line_ending: if crlf {
+                LineEnding::CrLf
+            } else {
+                LineEnding::Lf
+            },
+            final_newline: text.is_empty() || text.ends_with(b"\n"),
+            bom,
+            mixed_endings: endings.any(|other| other != crlf),
+        }
+    }
+
+    pub fn bom_bytes(&self) -> &'static [u8] {
+        if self.bom {
+            self.encoding.bom()
+        } else {
+            b""
+        }
+    }
+
+    // Short description for the status bar, e.g. "UTF-8 CRLF noeol BOM"
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} {}", self.encoding.name(), self.line_ending.name());
+        if self.mixed_endings {
+            description.push_str(" mixed");
+        }
+        if !self.final_newline {
+            description.push_str(" noeol");
+        }
+        if !self.bom_bytes().is_empty() {
+            description.push_str(" BOM");
+        }
+        description
+    }
+}
+
+// Splits text into rows the same way BufRead::lines does: a trailing line ending
+// does not start another row, and each row loses its '\n' or '\r\n'. A '\r'
+// that is not followed by a '\n' stays part of the row.
+pub fn split_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes
+        .split_inclusive(|b| *b == b'\n')
+        .map(strip_line_ending)
+}
+
+pub fn strip_line_ending(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(bytes: &[u8]) -> Vec<&[u8]> {
+        split_lines(bytes).collect()
+    }
+
+    #[test]
+    fn split() {
+        assert!(rows(b"").is_empty());
+        assert_eq!(rows(b"\n"), vec![b""]);
+        assert_eq!(rows(b"a\r\nb\nc"), vec![&b"a"[..], b"b", b"c"]);
+        // Only the '\r' of a CRLF is a line ending
+        assert_eq!(rows(b"a\rb\r\nc\r"), vec![&b"a\rb"[..], b"c\r"]);
+    }
+
+    #[test]
+    fn detect() {
+        let format = FileFormat::detect(b"a\r\nb\r\n", Encoding::Utf8, false);
+        assert!(format.line_ending == LineEnding::CrLf);
+        assert!(format.final_newline && !format.mixed_endings);
+        let format = FileFormat::detect(b"a\nb", Encoding::Utf8, false);
+        assert!(format.line_ending == LineEnding::Lf);
+        assert!(!format.final_newline && !format.mixed_endings);
+        assert!(FileFormat::new() == FileFormat::detect(b"", Encoding::Utf8, false));
+
+        let format = FileFormat::detect(b"a\nb\r\nc\n", Encoding::Utf8, false);
+        assert!(format.line_ending == LineEnding::Lf && format.mixed_endings);
+        assert_eq!(format.describe(), "UTF-8 LF mixed");
+        let format = FileFormat::detect(b"a\r\nb\n", Encoding::Utf8, true);
+        assert!(format.line_ending == LineEnding::CrLf && format.mixed_endings);
+        assert_eq!(format.describe(), "UTF-8 CRLF mixed BOM");
+    }
+
+    #[test]
+    fn encodings() {
+        assert!(Encoding::detect(b"plain \xc3\xa9") == Encoding::Utf8);
+        assert!(Encoding::detect(b"\xff\xfea\x00") == Encoding::Utf16Le);
+        assert!(Encoding::detect(b"\xfe\xff\x00a") == Encoding::Utf16Be);
+        assert!(Encoding::detect(b"caf\xe9") == Encoding::Windows1252);
+        // A character cut off at the end is only fine in a prefix
+        assert!(Encoding::detect(b"caf\xc3") == Encoding::Windows1252);
+        assert!(Encoding::detect_prefix(b"caf\xc3") == Encoding::Utf8);
+        assert!(Encoding::detect_prefix(b"\xff\xfea\x00") == Encoding::Utf16Le);
+
+        let text = "caf\u{e9} \u{20ac}5 \u{201c}q\u{201d}";
+        for encoding in Encoding::ALL.iter() {
+            let mut encoded = vec![];
+            match encoding.encode(text, &mut encoded) {
+                Ok(()) => assert_eq!(encoding.decode(&encoded).as_deref(), Some(text)),
+                Err(c) => assert!(*encoding == Encoding::Latin1 && c == '\u{20ac}'),
+            }
+        }
+        assert_eq!(
+            Encoding::Windows1252.decode(b"\x80\x81").as_deref(),
+            Some("\u{20ac}\u{81}")
+        );
+        assert_eq!(Encoding::Utf16Le.decode(b"a\x00b"), None);
+        assert_eq!(Encoding::Utf16Le.decode(b"\x00\xd8"), None);
+        assert!(Encoding::from_name("utf16le") == Some(Encoding::Utf16Le));
+        assert!(Encoding::from_name("CP1252") == Some(Encoding::Windows1252));
+        assert!(Encoding::from_name("ebcdic").is_none());
+    }
+}
@@ -0,0 +1,194 @@
+// *** GUTTER ***
+// The columns left of the text. The first holds a marker for each line, or '~'
+// past the end of the file, and line numbers follow when they are turned on.
+// Anything that wants to flag lines (changes, diagnostics, bookmarks) adds a
+// MarkerKind and shares the marker column.
+use crate::screen::Screen;
+use crate::theme::Theme;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    // Distance from the cursor line
+    Relative,
+    // Relative, except the cursor line which shows its absolute number
+    Hybrid,
+}
+
+impl LineNumbers {
+    pub fn next(self) -> LineNumbers {
+        match self {
+            LineNumbers::Off => LineNumbers::Absolute,
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Hybrid,
+            LineNumbers::Hybrid => LineNumbers::Off,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            LineNumbers::Off => "off",
+            LineNumbers::Absolute => "absolute",
+            LineNumbers::Relative => "relative",
+            LineNumbers::Hybrid => "hybrid",
+        }
+    }
+}
+
+// When a line has several markers, the one listed first is shown
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MarkerKind {
+    // Edited since the file was last opened or saved
+    Changed,
+}
+
+impl MarkerKind {
+    fn symbol(self) -> &'static str {
+        match self {
+            MarkerKind::Changed => "*",
+        }
+    }
+    // Highlight group the marker is drawn in
+    fn group(self) -> &'static str {
+        match self {
+            MarkerKind::Changed => "ui.marker.changed",
+        }
+    }
+}
+
+pub struct Gutter {
+    pub numbers: LineNumbers,
+    markers: BTreeMap<usize, BTreeSet<MarkerKind>>,
+}
+
+impl Gutter {
+    // Line numbers start off, Ctrl-N turns them on
+    pub fn new() -> Gutter {
+        Gutter {
+            numbers: LineNumbers::Off,
+            markers: BTreeMap::new(),
+        }
+    }
+
+    // Columns taken up for a file with line_count lines
+    pub fn width(&self, line_count: usize) -> usize {
+        if self.numbers == LineNumbers::Off {
+            return 1;
+        }
+        // Numbers are followed by a space to set them apart from the text
+        1 + line_count.max(1).to_string().len() + 1
+    }
+
+    pub fn mark(&mut self, kind: MarkerKind, y: usize) {
+        self.markers.entry(y).or_default().insert(kind);
+    }
+
+    pub fn unmark_all(&mut self, kind: MarkerKind) {
+        self.markers.retain(|_, kinds| {
+            kinds.remove(&kind);
+            !kinds.is_empty()
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.markers.clear();
+    }
+
+    // Keeps markers on their lines when count lines are inserted before line y
+    pub fn insert_lines(&mut self, y: usize, count: usize) {
+        let moved = self.markers.split_off(&y);
+        self.markers
+            .extend(moved.into_iter().map(|(line, kinds)| (line + count, kinds)));
+    }
+
+    // Drops the markers of count lines removed from line y on, and moves the
+    // markers after them up
+    pub fn remove_lines(&mut self, y: usize, count: usize) {
+        let mut moved = self.markers.split_off(&y);
+        let moved = moved.split_off(&(y + count));
+        self.markers
+            .extend(moved.into_iter().map(|(line, kinds)| (line - count, kinds)));
+    }
+
+    // Draws the gutter on screen row row for line y, or for a row past the end
+    // of the file if y is None. cy is the cursor line.
+    pub fn draw(
+        &self,
+        screen: &mut Screen,
+        theme: &Theme,
+        row: usize,
+        y: Option<usize>,
+        cy: usize,
+        width: usize,
+    ) {
+        let y = match y {
+            Some(y) => y,
+            None => {
+                screen.put(0, row, "~", theme.style("ui.eof"));
+                return;
+            }
+        };
+        if let Some(kind) = self.markers.get(&y).and_then(|kinds| kinds.iter().next()) {
+            screen.put(0, row, kind.symbol(), theme.style(kind.group()));
+        }
+        let number = match self.numbers {
+            LineNumbers::Off => return,
+            LineNumbers::Absolute => y + 1,
+            LineNumbers::Relative => y.abs_diff(cy),
+            LineNumbers::Hybrid if y == cy => y + 1,
+            LineNumbers::Hybrid => y.abs_diff(cy),
+        };
+        // The cursor line stands out from the dimmed numbers around it
+        let style = if y == cy {
+            theme.style("ui.linenumber.current")
+        } else {
+            theme.style("ui.linenumber")
+        };
+        let number = format!("{:>1$}", number, width - 2);
+        screen.put_str(1, row, &number, style);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked(gutter: &Gutter) -> Vec<usize> {
+        gutter.markers.keys().copied().collect()
+    }
+
+    #[test]
+    fn markers_follow_their_lines() {
+        let mut gutter = Gutter::new();
+        for y in [1, 3, 5, 8].iter() {
+            gutter.mark(MarkerKind::Changed, *y);
+        }
+        gutter.insert_lines(3, 2);
+        assert_eq!(marked(&gutter), vec![1, 5, 7, 10]);
+        gutter.insert_lines(11, 4);
+        assert_eq!(marked(&gutter), vec![1, 5, 7, 10]);
+
+        // Lines 5 and 6 go, taking their marker with them
+        gutter.remove_lines(5, 2);
+        assert_eq!(marked(&gutter), vec![1, 5, 8]);
+        gutter.remove_lines(0, 1);
+        assert_eq!(marked(&gutter), vec![0, 4, 7]);
+        gutter.remove_lines(7, 3);
+        assert_eq!(marked(&gutter), vec![0, 4]);
+
+        gutter.unmark_all(MarkerKind::Changed);
+        assert!(marked(&gutter).is_empty());
+    }
+
+    #[test]
+    fn width() {
+        let mut gutter = Gutter::new();
+        assert_eq!(gutter.width(500), 1);
+        gutter.numbers = LineNumbers::Absolute;
+        assert_eq!(gutter.width(0), 3);
+        assert_eq!(gutter.width(9), 3);
+        assert_eq!(gutter.width(10), 4);
+        assert_eq!(gutter.width(12345), 7);
+    }
+}
@@ -0,0 +1,423 @@
+// *** UNDO HISTORY ***
+// Every edit to the buffer is recorded as a list of Changes. Positions are byte
+// offsets into the text of a row, so a change can always be inverted exactly,
+// even when it merged or split grapheme clusters.
+//
+// The history is kept in a sidecar file next to the edited file so that it
+// survives closing the editor. The sidecar starts with a hash of the file
+// contents it was written for, and is ignored if the file has since changed.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SIDECAR_HEADER: &str = "vimacs-undo 1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    // text was inserted at byte offset at of row y
+    Insert { y: usize, at: usize, text: String },
+    // text was removed from byte offset at of row y
+    Delete { y: usize, at: usize, text: String },
+    // row y was split in two at byte offset at
+    Split { y: usize, at: usize },
+    // row y + 1 was appended to row y, which was at bytes long
+    Join { y: usize, at: usize },
+    // an empty row was inserted at index y
+    InsertRow { y: usize },
+    // the empty row at index y was removed
+    DeleteRow { y: usize },
+}
+
+impl Change {
+    fn to_line(&self) -> String {
+        match self {
+            Change::Insert { y, at, text } => format!("insert {} {} {}", y, at, escape(text)),
+            Change::Delete { y, at, text } => format!("delete {} {} {}", y, at, escape(text)),
+            Change::Split { y, at } => format!("split {} {}", y, at),
+            Change::Join { y, at } => format!("join {} {}", y, at),
+            Change::InsertRow { y } => format!("insert_row {}", y),
+            Change::DeleteRow { y } => format!("delete_row {}", y),
+        }
+    }
+    fn from_line(line: &str) -> Option<Change> {
+        let mut parts = line.splitn(4, ' ');
+        let kind = parts.next()?;
+        let y = parts.next()?.parse().ok()?;
+        let at = parts.next().and_then(|at| at.parse().ok());
+        let text = parts.next().and_then(unescape);
+        let change = match kind {
+            "insert" => Change::Insert {
+                y,
+                at: at?,
+                text: text?,
+            },
+            "delete" => Change::Delete {
+                y,
+                at: at?,
+                text: text?,
+            },
+            "split" => Change::Split { y, at: at? },
+            "join" => Change::Join { y, at: at? },
+            "insert_row" => Change::InsertRow { y },
+            "delete_row" => Change::DeleteRow { y },
+            _ => return None,
+        };
+        Some(change)
+    }
+    pub fn inverse(&self) -> Change {
+        match self.clone() {
+            Change::Insert { y, at, text } => Change::Delete { y, at, text },
+            Change::Delete { y, at, text } => Change::Insert { y, at, text },
+            Change::Split { y, at } => Change::Join { y, at },
+            Change::Join { y, at } => Change::Split { y, at },
+            Change::InsertRow { y } => Change::DeleteRow { y },
+            Change::DeleteRow { y } => Change::InsertRow { y },
+        }
+    }
+}
+
+// A single undoable step, along with the cursor (cx, cy) from before it was made
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub changes: Vec<Change>,
+    pub cursor: (usize, usize),
+    typing: bool,
+}
+
+pub struct History {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    // Length of the undo stack when the file was last saved, or None if that
+    // state can no longer be reached
+    saved: Option<usize>,
+    // Set when the next typed character must start a new step
+    sealed: bool,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            undo: vec![],
+            redo: vec![],
+            saved: Some(0),
+            sealed: false,
+        }
+    }
+    // Records changes that were just applied. Consecutive typing on the same row
+    // is merged into a single step.
+    pub fn record(&mut self, changes: Vec<Change>, cursor: (usize, usize), typing: bool) {
+        self.redo.clear();
+        if let Some(saved) = self.saved {
+            if saved > self.undo.len() {
+                self.saved = None;
+            }
+        }
+        if typing && !self.sealed && self.extend_typing(&changes) {
+            return;
+        }
+        self.undo.push(Step {
+            changes,
+            cursor,
+            typing,
+        });
+        self.sealed = false;
+    }
+    fn extend_typing(&mut self, changes: &[Change]) -> bool {
+        let last = match self.undo.last_mut() {
+            Some(step) if step.typing => step,
+            _ => return false,
+        };
+        if let (
+            [Change::Insert { y, at, text }],
+            Some(Change::Insert {
+                y: last_y,
+                at: last_at,
+                text: last_text,
+            }),
+        ) = (changes, last.changes.last_mut())
+        {
+            if y == last_y && *at == *last_at + last_text.len() {
+                last_text.push_str(text);
+                return true;
+            }
+        }
+        false
+    }
+    // Moves the latest step onto the redo stack and returns it. The caller applies
+    // the inverse of its changes in reverse order.
+    pub fn undo(&mut self) -> Option<Step> {
+        let step = self.undo.pop()?;
+        self.redo.push(step.clone());
+        self.sealed = true;
+        Some(step)
+    }
+    // Moves the latest undone step back onto the undo stack and returns it
+    pub fn redo(&mut self) -> Option<Step> {
+        let step = self.redo.pop()?;
+        self.undo.push(step.clone());
+        self.sealed = true;
+        Some(step)
+    }
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.undo.len());
+        self.sealed = true;
+    }
+    // Called when the file changed in a way the history does not record, so no
+    // step matches what is on disk any more
+    pub fn forget_saved(&mut self) {
+        self.saved = None;
+        self.sealed = true;
+    }
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.undo.len())
+    }
+
+    // Writes both stacks to the sidecar at path. hash is the content_hash of the
+    // file as it was just saved.
+    pub fn save(&self, path: &Path, hash: u64) -> io::Result<()> {
+        let mut out = format!("{}\nhash {:016x}\n", SIDECAR_HEADER, hash);
+        for (name, stack) in [("undo", &self.undo), ("redo", &self.redo)].iter() {
+            out.push_str(&format!("{} {}\n", name, stack.len()));
+            for step in stack.iter() {
+                out.push_str(&format!(
+                    "step {} {} {} {}\n",
+                    step.cursor.0,
+                    step.cursor.1,
+                    step.typing as u8,
+                    step.changes.len()
+                ));
+                for change in step.changes.iter() {
+                    out.push_str(&change.to_line());
+                    out.push('\n');
+                }
+            }
+        }
+        fs::write(path, out)
+    }
+
+    // Loads the history saved at path. Returns None if there is no sidecar, it
+    // is malformed, or it was written for different file contents.
+    pub fn load(path: &Path, hash: u64) -> Option<History> {
+        let contents = fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        if lines.next()? != SIDECAR_HEADER {
+            return None;
+        }
+        if lines.next()? != format!("hash {:016x}", hash) {
+            return None;
+        }
+        let undo = read_stack(&mut lines, "undo")?;
+        let redo = read_stack(&mut lines, "redo")?;
+        Some(History {
+            saved: Some(undo.len()),
+            undo,
+            redo,
+            sealed: true,
+        })
+    }
+}
+
+fn read_stack<'a>(lines: &mut impl Iterator<Item = &'a str>, name: &str) -> Option<Vec<Step>> {
+    let count: usize = lines.next()?.strip_prefix(name)?.trim().parse().ok()?;
+    let mut stack = Vec::with_capacity(count);
+    for _ in 0..count {
+        let fields: Vec<usize> = lines
+            .next()?
+            .strip_prefix("step ")?
+            .split(' ')
+            .map(|field| field.parse().ok())
+            .collect::<Option<_>>()?;
+        if fields.len() != 4 {
+            return None;
+        }
+        let changes = (0..fields[3])
+            .map(|_| Change::from_line(lines.next()?))
+            .collect::<Option<_>>()?;
+        stack.push(Step {
+            changes,
+            cursor: (fields[0], fields[1]),
+            typing: fields[2] != 0,
+        });
+    }
+    Some(stack)
+}
+
+// FNV-1a, which unlike std's hasher is guaranteed to stay the same between builds
+pub fn content_hash<'a, I: Iterator<Item = &'a [u8]>>(chunks: I) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in chunks.flat_map(|chunk| chunk.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// Keeps each change on a single line of the sidecar
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(y: usize, at: usize, text: &str) -> Change {
+        Change::Insert {
+            y,
+            at,
+            text: String::from(text),
+        }
+    }
+
+    #[test]
+    fn typing_merges_until_sealed() {
+        let mut history = History::new();
+        history.record(vec![insert(0, 0, "a")], (0, 0), true);
+        history.record(vec![insert(0, 1, "b")], (1, 0), true);
+        // Typing somewhere else starts a new step
+        history.record(vec![insert(1, 0, "c")], (0, 1), true);
+        history.record(vec![Change::Split { y: 1, at: 1 }], (1, 1), false);
+        history.record(vec![insert(2, 0, "d")], (0, 2), true);
+        history.mark_saved();
+        history.record(vec![insert(2, 1, "e")], (1, 2), true);
+
+        let steps: Vec<Vec<Change>> = std::iter::from_fn(|| history.undo())
+            .map(|step| step.changes)
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                vec![insert(2, 1, "e")],
+                vec![insert(2, 0, "d")],
+                vec![Change::Split { y: 1, at: 1 }],
+                vec![insert(1, 0, "c")],
+                vec![insert(0, 0, "ab")],
+            ]
+        );
+    }
+
+    #[test]
+    fn saved_state() {
+        let mut history = History::new();
+        assert!(history.is_saved());
+        history.record(vec![insert(0, 0, "a")], (0, 0), false);
+        assert!(!history.is_saved());
+        history.undo().unwrap();
+        assert!(history.is_saved());
+        history.redo().unwrap();
+        history.mark_saved();
+        assert_eq!(history.undo().unwrap().cursor, (0, 0));
+        assert!(!history.is_saved());
+        // A new edit after undoing past the save makes it unreachable
+        history.record(vec![insert(0, 0, "b")], (0, 0), false);
+        assert!(history.undo().is_some() && !history.is_saved());
+        assert!(history.undo().is_none() && !history.is_saved());
+        history.forget_saved();
+        assert!(!history.is_saved());
+    }
+
+    #[test]
+    fn inverse() {
+        let changes = [
+            insert(0, 1, "x"),
+            Change::Split { y: 2, at: 3 },
+            Change::InsertRow { y: 4 },
+        ];
+        for change in changes.iter() {
+            assert_ne!(change.inverse(), *change);
+            assert_eq!(change.inverse().inverse(), *change);
+        }
+        assert_eq!(changes[1].inverse(), Change::Join { y: 2, at: 3 });
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let path = std::env::temp_dir().join(format!("vimacs-history-{}", std::process::id()));
+        let mut history = History::new();
+        history.record(vec![insert(0, 0, "a\\b\nc\r")], (0, 0), true);
+        history.record(
+            vec![Change::Join { y: 0, at: 5 }, Change::DeleteRow { y: 3 }],
+            (5, 0),
+            false,
+        );
+        history.record(vec![insert(1, 2, "")], (2, 1), false);
+        history.undo().unwrap();
+        history.save(&path, 42).unwrap();
+
+        assert!(History::load(&path, 43).is_none());
+        let mut loaded = History::load(&path, 42).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_saved());
+        let redo = loaded.redo().unwrap();
+        assert_eq!(
+            (redo.changes, redo.cursor),
+            (vec![insert(1, 2, "")], (2, 1))
+        );
+        loaded.undo().unwrap();
+        let step = loaded.undo().unwrap();
+        assert_eq!(step.cursor, (5, 0));
+        assert_eq!(step.changes[1], Change::DeleteRow { y: 3 });
+        assert_eq!(
+            loaded.undo().unwrap().changes,
+            vec![insert(0, 0, "a\\b\nc\r")]
+        );
+        assert!(loaded.undo().is_none());
+    }
+
+    #[test]
+    fn malformed_sidecar() {
+        let path = std::env::temp_dir().join(format!("vimacs-bad-{}", std::process::id()));
+        let hash = "vimacs-undo 1\nhash 000000000000002a\n";
+        for contents in [
+            "",
+            "vimacs-undo 2\nhash 000000000000002a\nundo 0\nredo 0\n",
+            "vimacs-undo 1\nhash 000000000000002a\nundo 1\nredo 0\n",
+            "vimacs-undo 1\nhash 000000000000002a\nundo 1\nstep 0 0 0 1\nmove 1 2\nredo 0\n",
+        ]
+        .iter()
+        {
+            fs::write(&path, contents).unwrap();
+            assert!(History::load(&path, 42).is_none(), "{:?}", contents);
+        }
+        fs::write(&path, format!("{}undo 0\nredo 0\n", hash)).unwrap();
+        assert!(History::load(&path, 42).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hash_ignores_chunking() {
+        let whole = content_hash(std::iter::once(&b"one\ntwo\n"[..]));
+        let parts = [&b"on"[..], b"e\nt", b"", b"wo\n"];
+        assert_eq!(content_hash(parts.iter().copied()), whole);
+        assert_ne!(content_hash(std::iter::once(&b"one\ntwo"[..])), whole);
+        // FNV-1a of nothing is its offset basis
+        assert_eq!(content_hash(std::iter::empty()), 0xcbf2_9ce4_8422_2325);
+    }
+}
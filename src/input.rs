@@ -0,0 +1,538 @@
+// *** INPUT ***
+// Decodes the bytes the terminal sends into keys and mouse events. Printable
+// characters arrive as UTF-8, Ctrl+letter as a control byte, Alt+key as Escape
+// followed by the key, and everything else as a CSI ("\x1b[") or SS3 ("\x1bO")
+// escape sequence.
+use std::collections::VecDeque;
+use std::env;
+use std::ops::BitOr;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+// Sent by the terminal after pasted text in bracketed paste mode
+const PASTE_END: &[u8] = b"\x1b[201~";
+const ESCAPE: u8 = 0x1b;
+// How long to wait after an Escape for the rest of an escape sequence. A lone
+// Escape is only reported once this runs out.
+const DEFAULT_ESC_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyCode {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Insert,
+    Delete,
+    Backspace,
+    Enter,
+    Tab,
+    Escape,
+    // Function keys F1 to F12
+    F(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1);
+    pub const ALT: Modifiers = Modifiers(1 << 1);
+    pub const CTRL: Modifiers = Modifiers(1 << 2);
+
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // xterm encodes modifiers in escape sequences as one plus a bitmask of
+    // shift, alt and ctrl, which is the same order used here
+    fn from_param(param: u32) -> Modifiers {
+        Modifiers((param.saturating_sub(1) & 0b111) as u8)
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub modifiers: Modifiers,
+}
+
+impl Key {
+    pub fn new(code: KeyCode, modifiers: Modifiers) -> Key {
+        Key { code, modifiers }
+    }
+    pub fn plain(code: KeyCode) -> Key {
+        Key::new(code, Modifiers::NONE)
+    }
+    pub fn ctrl(c: char) -> Key {
+        Key::new(KeyCode::Char(c), Modifiers::CTRL)
+    }
+    // The character to insert for this key, if it types one
+    pub fn typed_char(&self) -> Option<char> {
+        if self.modifiers.contains(Modifiers::CTRL) || self.modifiers.contains(Modifiers::ALT) {
+            return None;
+        }
+        match self.code {
+            KeyCode::Char(c) if !c.is_control() => Some(c),
+            // Shift-Tab is not a tab
+            KeyCode::Tab if !self.modifiers.contains(Modifiers::SHIFT) => Some('\t'),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseKind {
+    Press(MouseButton),
+    // The mouse moved while the button was held down
+    Drag(MouseButton),
+    Release(MouseButton),
+    ScrollUp,
+    ScrollDown,
+}
+
+// A mouse event at a screen cell, counted from zero at the top left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mouse {
+    pub kind: MouseKind,
+    pub column: usize,
+    pub row: usize,
+    pub modifiers: Modifiers,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Key(Key),
+    Mouse(Mouse),
+    // Text pasted while bracketed paste mode is on, with line endings as '\n'
+    Paste(String),
+}
+
+// Reads keys straight from the terminal's file descriptor. Input is not read
+// through std's buffered Stdin, as bytes sitting in its buffer would be
+// invisible to poll.
+pub struct Input {
+    fd: RawFd,
+    pending: VecDeque<u8>,
+    esc_timeout: Duration,
+}
+
+impl Input {
+    pub fn new(fd: RawFd) -> Input {
+        // Like curses, ESCDELAY sets the escape timeout in milliseconds
+        let esc_timeout = env::var("ESCDELAY")
+            .ok()
+            .and_then(|delay| delay.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_ESC_TIMEOUT);
+        Input {
+            fd,
+            pending: VecDeque::new(),
+            esc_timeout,
+        }
+    }
+
+    // Waits up to timeout for input, returning whether any is available. Returns
+    // early if a signal arrives.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        if !self.pending.is_empty() {
+            return true;
+        }
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) > 0 }
+    }
+
+    // Returns the next byte, waiting at most timeout for it if one is given.
+    // Returns None on timeout or when a signal interrupts the wait.
+    pub fn read_byte(&mut self, timeout: Option<Duration>) -> Option<u8> {
+        if self.pending.is_empty() {
+            if let Some(timeout) = timeout {
+                if !self.wait(timeout) {
+                    return None;
+                }
+            }
+            let mut buf = [0; 64];
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, 64) };
+            if len <= 0 {
+                return None;
+            }
+            self.pending.extend(&buf[..len as usize]);
+        }
+        self.pending.pop_front()
+    }
+
+    // Blocks until a key is pressed or the mouse is used. Returns None if a
+    // signal interrupted the read, or the input was not something this decoder
+    // understands.
+    pub fn read_event(&mut self) -> Option<Event> {
+        let byte = self.read_byte(None)?;
+        if byte != ESCAPE {
+            return self.decode_byte(byte).map(Event::Key);
+        }
+        let next = match self.read_byte(Some(self.esc_timeout)) {
+            Some(next) => next,
+            None => return Some(Event::Key(Key::plain(KeyCode::Escape))),
+        };
+        let key = match next {
+            b'[' => match self.read_byte(Some(self.esc_timeout)) {
+                Some(byte) => return self.read_csi(byte),
+                None => Key::new(KeyCode::Char('['), Modifiers::ALT),
+            },
+            b'O' => match self.read_byte(Some(self.esc_timeout)) {
+                Some(byte) => ss3_key(byte, Modifiers::NONE)?,
+                None => Key::new(KeyCode::Char('O'), Modifiers::ALT),
+            },
+            ESCAPE => Key::new(KeyCode::Escape, Modifiers::ALT),
+            // Escape followed by any other key is how terminals send Alt+key
+            _ => {
+                let key = self.decode_byte(next)?;
+                Key::new(key.code, key.modifiers | Modifiers::ALT)
+            }
+        };
+        Some(Event::Key(key))
+    }
+
+    // Like read_event, but ignores the mouse and pastes
+    pub fn read_key(&mut self) -> Option<Key> {
+        match self.read_event()? {
+            Event::Key(key) => Some(key),
+            Event::Mouse(_) | Event::Paste(_) => None,
+        }
+    }
+
+    // Reads pasted text up to the "\x1b[201~" that ends it. The terminal sends
+    // the whole paste at once, so there is no timeout.
+    fn read_paste(&mut self) -> Option<Event> {
+        let mut bytes = Vec::new();
+        while !bytes.ends_with(PASTE_END) {
+            bytes.push(self.read_byte(None)?);
+        }
+        bytes.truncate(bytes.len() - PASTE_END.len());
+        // Terminals send line breaks in pasted text as '\r', like the Enter key
+        let text = String::from_utf8_lossy(&bytes)
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        Some(Event::Paste(text))
+    }
+
+    // Decodes a key that is not an escape sequence
+    fn decode_byte(&mut self, byte: u8) -> Option<Key> {
+        let key = match byte {
+            b'\r' | b'\n' => Key::plain(KeyCode::Enter),
+            b'\t' => Key::plain(KeyCode::Tab),
+            0x7f => Key::plain(KeyCode::Backspace),
+            0 => Key::ctrl(' '),
+            // Ctrl+A to Ctrl+Z, then Ctrl+\ ] ^ _
+            1..=0x1f => Key::ctrl(((byte + 0x40) as char).to_ascii_lowercase()),
+            0x80..=0xff => Key::plain(KeyCode::Char(self.read_utf8(byte)?)),
+            _ => Key::plain(KeyCode::Char(byte as char)),
+        };
+        Some(key)
+    }
+
+    // Decodes the rest of a multi-byte UTF-8 sequence whose leading byte was already
+    // read. Returns None for malformed input so the keypress is ignored.
+    fn read_utf8(&mut self, first: u8) -> Option<char> {
+        let len = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return None,
+        };
+        let mut buf = [first, 0, 0, 0];
+        for byte in buf.iter_mut().take(len).skip(1) {
+            *byte = self.read_byte(Some(self.esc_timeout))?;
+        }
+        std::str::from_utf8(&buf[..len]).ok()?.chars().next()
+    }
+
+    // Reads a CSI sequence, whose first byte after "\x1b[" is first. It is made
+    // of parameter bytes, then intermediate bytes, and ends with a final byte.
+    fn read_csi(&mut self, first: u8) -> Option<Event> {
+        // The Linux console sends F1 to F5 as "\x1b[[A" to "\x1b[[E"
+        if first == b'[' {
+            return match self.read_byte(Some(self.esc_timeout))? {
+                final_byte @ b'A'..=b'E' => {
+                    Some(Event::Key(Key::plain(KeyCode::F(final_byte - b'A' + 1))))
+                }
+                _ => None,
+            };
+        }
+        let mut params = Vec::new();
+        let mut byte = first;
+        while !(0x40..=0x7e).contains(&byte) {
+            if !(0x20..=0x3f).contains(&byte) {
+                // Not part of a CSI sequence, so the sequence was cut off
+                return None;
+            }
+            params.push(byte);
+            byte = self.read_byte(Some(self.esc_timeout))?;
+        }
+        let mouse = params.first() == Some(&b'<');
+        let params: Vec<u32> = std::str::from_utf8(&params[mouse as usize..])
+            .ok()?
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        if mouse {
+            return sgr_mouse(&params, byte == b'm').map(Event::Mouse);
+        }
+        let modifiers = Modifiers::from_param(params.get(1).copied().unwrap_or(1));
+        match byte {
+            b'~' if params[0] == 200 => self.read_paste(),
+            b'~' => {
+                let code = match params[0] {
+                    1 | 7 => KeyCode::Home,
+                    2 => KeyCode::Insert,
+                    3 => KeyCode::Delete,
+                    4 | 8 => KeyCode::End,
+                    5 => KeyCode::PageUp,
+                    6 => KeyCode::PageDown,
+                    11..=15 => KeyCode::F((params[0] - 10) as u8),
+                    17..=21 => KeyCode::F((params[0] - 11) as u8),
+                    23 | 24 => KeyCode::F((params[0] - 12) as u8),
+                    _ => return None,
+                };
+                Some(Event::Key(Key::new(code, modifiers)))
+            }
+            b'Z' => Some(Event::Key(Key::new(KeyCode::Tab, Modifiers::SHIFT))),
+            _ => ss3_key(byte, modifiers).map(Event::Key),
+        }
+    }
+}
+
+// Decodes an SGR mouse report, "\x1b[<{button};{column};{row}M", which ends in
+// 'm' instead when a button is released. The button number also carries the
+// modifiers held, whether the mouse moved, and whether the wheel was used.
+fn sgr_mouse(params: &[u32], released: bool) -> Option<Mouse> {
+    if params.len() != 3 {
+        return None;
+    }
+    let code = params[0];
+    let button = match code & 0b11 {
+        0 => MouseButton::Left,
+        1 => MouseButton::Middle,
+        2 => MouseButton::Right,
+        _ => return None,
+    };
+    let kind = if code & 64 != 0 {
+        match button {
+            MouseButton::Left => MouseKind::ScrollUp,
+            MouseButton::Middle => MouseKind::ScrollDown,
+            // Horizontal scrolling
+            MouseButton::Right => return None,
+        }
+    } else if released {
+        MouseKind::Release(button)
+    } else if code & 32 != 0 {
+        MouseKind::Drag(button)
+    } else {
+        MouseKind::Press(button)
+    };
+    Some(Mouse {
+        kind,
+        column: params[1].saturating_sub(1) as usize,
+        row: params[2].saturating_sub(1) as usize,
+        modifiers: Modifiers(((code >> 2) & 0b111) as u8),
+    })
+}
+
+// Keys that share a final byte between SS3 sequences and CSI sequences
+fn ss3_key(byte: u8, modifiers: Modifiers) -> Option<Key> {
+    let code = match byte {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        b'P'..=b'S' => KeyCode::F(byte - b'P' + 1),
+        _ => return None,
+    };
+    Some(Key::new(code, modifiers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes bytes as if the terminal had sent them all at once. The fd is not
+    // valid, so reading past the end of bytes times out.
+    fn events(bytes: &[u8]) -> Vec<Event> {
+        let mut input = Input {
+            fd: -1,
+            pending: bytes.iter().copied().collect(),
+            esc_timeout: Duration::ZERO,
+        };
+        let mut events = vec![];
+        while !input.pending.is_empty() {
+            if let Some(event) = input.read_event() {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn key(bytes: &[u8]) -> Key {
+        match events(bytes)[..] {
+            [Event::Key(key)] => key,
+            ref other => panic!("{:?} decoded as {:?}", bytes, other),
+        }
+    }
+
+    fn mouse(bytes: &[u8]) -> Option<Mouse> {
+        match events(bytes)[..] {
+            [Event::Mouse(mouse)] => Some(mouse),
+            [] => None,
+            ref other => panic!("{:?} decoded as {:?}", bytes, other),
+        }
+    }
+
+    #[test]
+    fn plain_keys() {
+        assert_eq!(key(b"a"), Key::plain(KeyCode::Char('a')));
+        assert_eq!(
+            key("\u{e9}".as_bytes()),
+            Key::plain(KeyCode::Char('\u{e9}'))
+        );
+        assert_eq!(key(b"\r"), Key::plain(KeyCode::Enter));
+        assert_eq!(key(b"\x7f"), Key::plain(KeyCode::Backspace));
+        assert_eq!(key(b"\x11"), Key::ctrl('q'));
+        assert_eq!(key(b"\x1b"), Key::plain(KeyCode::Escape));
+        assert_eq!(key(b"\x1bx"), Key::new(KeyCode::Char('x'), Modifiers::ALT));
+        assert_eq!(key(b"\x1b\x1b"), Key::new(KeyCode::Escape, Modifiers::ALT));
+    }
+
+    #[test]
+    fn csi_and_ss3() {
+        assert_eq!(key(b"\x1b[A"), Key::plain(KeyCode::Up));
+        assert_eq!(key(b"\x1bOD"), Key::plain(KeyCode::Left));
+        assert_eq!(key(b"\x1bOQ"), Key::plain(KeyCode::F(2)));
+        assert_eq!(key(b"\x1b[1;5C"), Key::new(KeyCode::Right, Modifiers::CTRL));
+        let shift_alt = Modifiers::SHIFT | Modifiers::ALT;
+        assert_eq!(key(b"\x1b[1;4H"), Key::new(KeyCode::Home, shift_alt));
+        assert_eq!(key(b"\x1b[3~"), Key::plain(KeyCode::Delete));
+        assert_eq!(key(b"\x1b[4~"), Key::plain(KeyCode::End));
+        assert_eq!(
+            key(b"\x1b[6;2~"),
+            Key::new(KeyCode::PageDown, Modifiers::SHIFT)
+        );
+        assert_eq!(key(b"\x1b[15~"), Key::plain(KeyCode::F(5)));
+        assert_eq!(key(b"\x1b[24~"), Key::plain(KeyCode::F(12)));
+        assert_eq!(key(b"\x1b[[B"), Key::plain(KeyCode::F(2)));
+        assert_eq!(key(b"\x1b[Z"), Key::new(KeyCode::Tab, Modifiers::SHIFT));
+        // Unknown and cut off sequences are dropped without eating the next key
+        assert_eq!(
+            events(b"\x1b[99~a"),
+            vec![Event::Key(Key::plain(KeyCode::Char('a')))]
+        );
+        assert_eq!(events(b"\x1b[1;\x01"), vec![]);
+    }
+
+    #[test]
+    fn mouse_reports() {
+        let press = mouse(b"\x1b[<0;5;11M").unwrap();
+        assert_eq!(
+            (press.kind, press.column, press.row),
+            (MouseKind::Press(MouseButton::Left), 4, 10)
+        );
+        assert_eq!(press.modifiers, Modifiers::NONE);
+        let release = mouse(b"\x1b[<2;1;1m").unwrap();
+        assert_eq!(release.kind, MouseKind::Release(MouseButton::Right));
+        let drag = mouse(b"\x1b[<48;3;4M").unwrap();
+        assert_eq!(drag.kind, MouseKind::Drag(MouseButton::Left));
+        assert_eq!(drag.modifiers, Modifiers::CTRL);
+        assert_eq!(mouse(b"\x1b[<64;1;1M").unwrap().kind, MouseKind::ScrollUp);
+        assert_eq!(mouse(b"\x1b[<65;1;1M").unwrap().kind, MouseKind::ScrollDown);
+        assert_eq!(mouse(b"\x1b[<66;1;1M"), None);
+        assert_eq!(mouse(b"\x1b[<0;1M"), None);
+    }
+
+    #[test]
+    fn paste() {
+        assert_eq!(
+            events(b"\x1b[200~one\r\ntwo\rthree\x1b[201~x"),
+            vec![
+                Event::Paste(String::from("one\ntwo\nthree")),
+                Event::Key(Key::plain(KeyCode::Char('x'))),
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_chars() {
+        assert_eq!(Key::plain(KeyCode::Char('a')).typed_char(), Some('a'));
+        assert_eq!(Key::plain(KeyCode::Tab).typed_char(), Some('\t'));
+        assert_eq!(Key::new(KeyCode::Tab, Modifiers::SHIFT).typed_char(), None);
+        assert_eq!(Key::ctrl('a').typed_char(), None);
+        assert_eq!(
+            Key::new(KeyCode::Char('a'), Modifiers::ALT).typed_char(),
+            None
+        );
+    }
+
+    #[test]
+    fn utf8() {
+        assert_eq!(key("é".as_bytes()), Key::plain(KeyCode::Char('é')));
+        assert_eq!(key("漢".as_bytes()), Key::plain(KeyCode::Char('漢')));
+        assert_eq!(key("🦀".as_bytes()), Key::plain(KeyCode::Char('🦀')));
+        // Cut short, or not UTF-8 at all
+        assert!(events(&"漢".as_bytes()[..2]).is_empty());
+        assert!(events(&[0xff]).is_empty());
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (read_end, write_end) = (fds[0], fds[1]);
+        let write = move |bytes: &[u8]| unsafe {
+            libc::write(
+                write_end,
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+            )
+        };
+        let bytes = "漢".as_bytes();
+        write(&bytes[..1]);
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            write(&bytes[1..]);
+        });
+        let mut input = Input {
+            fd: read_end,
+            pending: VecDeque::new(),
+            esc_timeout: Duration::from_secs(1),
+        };
+        assert_eq!(input.read_key(), Some(Key::plain(KeyCode::Char('漢'))));
+        writer.join().unwrap();
+        unsafe {
+            libc::close(read_end);
+            libc::close(write_end);
+        }
+    }
+}
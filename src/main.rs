@@ -1,9 +1,21 @@
 use std::env;
+mod buffer;
+mod config;
 mod editor;
+mod format;
+mod gutter;
+mod history;
+mod input;
+mod mapped;
 mod row;
+mod rules;
+mod save;
+mod screen;
+mod swap;
+mod syntax;
+mod theme;
 use editor::Editor;
 
-
 // *** INIT ***
 fn main() {
     let mut editor = Editor::new();
@@ -0,0 +1,168 @@
+use crate::format::{split_lines, strip_line_ending, Encoding, FileFormat};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Number of lines the indexer finds before publishing them to the editor
+const INDEX_BATCH: usize = 1 << 16;
+// The encoding and line endings are detected from this many bytes at the start
+// of the file, since scanning all of it would hold up opening it. Line endings
+// that differ further on are found by the indexer.
+const DETECT_LEN: usize = 1 << 16;
+
+// A read-only view of a file too large to load up front. The file is memory
+// mapped and a background thread records where each line ends, so the editor
+// can show the first screen immediately and only decodes the lines it draws.
+pub struct MappedFile {
+    map: Arc<Mmap>,
+    format: FileFormat,
+    // Byte offset where the text starts, past any BOM
+    start: usize,
+    // Byte offset just past the end of each line found so far
+    ends: Arc<Mutex<Vec<usize>>>,
+    indexed: Arc<AtomicBool>,
+    // Set by the indexer if some line ends differently from the first one
+    mixed: Arc<AtomicBool>,
+}
+
+impl MappedFile {
+    pub fn open(file: &File) -> io::Result<MappedFile> {
+        // The map is only ever read. If another program truncates the file while it
+        // is open, reading the missing pages faults; that is the price of not copying
+        // a multi-gigabyte file into memory.
+        let map = Arc::new(unsafe { Mmap::map(file)? });
+        // Only UTF-8 files are mapped, decoding anything else up front would
+        // defeat the point of mapping them
+        let prefix = &map[..std::cmp::min(map.len(), DETECT_LEN)];
+        if Encoding::detect_prefix(prefix) != Encoding::Utf8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not UTF-8"));
+        }
+        let bom = map.starts_with(Encoding::Utf8.bom());
+        let start = if bom { Encoding::Utf8.bom().len() } else { 0 };
+        let mut format = FileFormat::detect(
+            &map[start..std::cmp::min(map.len(), start + DETECT_LEN)],
+            Encoding::Utf8,
+            bom,
+        );
+        format.final_newline = map.len() == start || map.ends_with(b"\n");
+        let ends = Arc::new(Mutex::new(Vec::new()));
+        let indexed = Arc::new(AtomicBool::new(false));
+        let mixed = Arc::new(AtomicBool::new(false));
+        let (thread_map, thread_ends, thread_indexed, thread_mixed) =
+            (map.clone(), ends.clone(), indexed.clone(), mixed.clone());
+        thread::spawn(move || {
+            index_lines(
+                &thread_map,
+                start,
+                &thread_ends,
+                &thread_indexed,
+                &thread_mixed,
+            )
+        });
+        Ok(MappedFile {
+            map,
+            format,
+            start,
+            ends,
+            indexed,
+            mixed,
+        })
+    }
+
+    // Number of lines indexed so far
+    pub fn len(&self) -> usize {
+        self.ends.lock().unwrap().len()
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.indexed.load(Ordering::Acquire)
+    }
+
+    pub fn format(&self) -> FileFormat {
+        self.format
+    }
+
+    // Whether the file turned out to mix line endings after the start its format
+    // was detected from. Returns true only once, and only after it is indexed.
+    pub fn take_mixed_endings(&self) -> bool {
+        self.is_indexed() && !self.format.mixed_endings && self.mixed.swap(false, Ordering::AcqRel)
+    }
+
+    // Text of line y without its line ending. Invalid UTF-8 is replaced rather
+    // than rejected so a corrupt log can still be viewed.
+    pub fn line(&self, y: usize) -> String {
+        let (start, end) = {
+            let ends = self.ends.lock().unwrap();
+            (if y == 0 { self.start } else { ends[y - 1] }, ends[y])
+        };
+        String::from_utf8_lossy(strip_line_ending(&self.map[start..end])).into_owned()
+    }
+
+    // Byte offset where line y starts
+    fn offset(&self, ends: &[usize], y: usize) -> usize {
+        if y == 0 {
+            self.start
+        } else {
+            ends[y - 1]
+        }
+    }
+
+    // The bytes of lines start..end, or of every line from start on if end is
+    // None, even those not indexed yet
+    fn range(&self, start: usize, end: Option<usize>) -> (usize, usize) {
+        let ends = self.ends.lock().unwrap();
+        let from = self.offset(&ends, start);
+        let to = end.map_or(self.map.len(), |end| self.offset(&ends, end));
+        (from, to)
+    }
+
+    // Lines start..end without their line endings, split the same way a file
+    // read into memory is
+    pub fn rows(&self, start: usize, end: Option<usize>) -> impl Iterator<Item = &[u8]> {
+        let (from, to) = self.range(start, end);
+        split_lines(&self.map[from..to])
+    }
+
+    // Size in bytes of lines start..end, line endings included
+    pub fn span(&self, start: usize, end: Option<usize>) -> usize {
+        let (from, to) = self.range(start, end);
+        to - from
+    }
+
+    // The raw contents of the file, including any BOM and '\r's
+    pub fn bytes(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+fn index_lines(
+    map: &Mmap,
+    start: usize,
+    ends: &Mutex<Vec<usize>>,
+    indexed: &AtomicBool,
+    mixed: &AtomicBool,
+) {
+    let mut batch = Vec::with_capacity(INDEX_BATCH);
+    let mut first_crlf = None;
+    for (i, byte) in map.iter().enumerate().skip(start) {
+        if *byte == b'\n' {
+            let crlf = i > start && map[i - 1] == b'\r';
+            if *first_crlf.get_or_insert(crlf) != crlf {
+                mixed.store(true, Ordering::Relaxed);
+            }
+            batch.push(i + 1);
+            if batch.len() == INDEX_BATCH {
+                ends.lock().unwrap().append(&mut batch);
+            }
+        }
+    }
+    // A last line without a trailing newline is still a line
+    if map.len() > start && !map.ends_with(b"\n") {
+        batch.push(map.len());
+    }
+    ends.lock().unwrap().append(&mut batch);
+    indexed.store(true, Ordering::Release);
+}
@@ -1,272 +1,279 @@
-use crate::editor::{
-    Highlight, Syntax, ALLOW_SINGLE_QUOTE, HIGHLIGHT_NUMBERS, HIGHLIGHT_STRINGS, TAB_STOP,
-};
+use crate::editor::TAB_STOP;
+use crate::rules::Open;
+use crate::syntax::{Highlight, Syntax};
+use std::collections::{BTreeMap, BTreeSet};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Only this many bytes at the start of a row are highlighted, so that editing very
+// long rows, like minified code, stays quick. The rest is drawn as normal text.
+// The row after starts in whatever was left open at the end of that prefix,
+// so long lines inside a comment or docstring don't end it.
+const MAX_HIGHLIGHT_LEN: usize = 10_000;
+
+// A row addresses its text by grapheme cluster: cx is a grapheme index into text,
+// rx is a display column into rendered, and highlight holds one entry per char of rendered
+// as far as it is highlighted.
 pub struct Row {
     text: String,
     rendered: String,
     highlight: Vec<Highlight>,
+    // What the row was highlighted starting in, and the contexts like a comment
+    // or string still open at its end
+    start: Open,
+    open: Open,
 }
-fn is_separator(c: char) -> bool {
-    for ch in " \0,.()+-/*=~%<>[];".chars() {
-        if c == ch {
-            return true;
-        }
-    }
-    false
-}
-
-fn is_separator_or_none(c: Option<char>) -> bool {
-    match c {
-        Some(ch) => is_separator(ch),
-        _ => true,
+// Number of screen columns taken up by a grapheme cluster. Control characters are
+// drawn as a single inverted symbol, wide (CJK) clusters take two columns and
+// combining marks add nothing to the width of their base character.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    match grapheme.chars().next() {
+        Some(c) if c.is_control() => 1,
+        _ => UnicodeWidthStr::width(grapheme),
     }
 }
 
 impl Row {
-    pub fn new() -> Row {
+    // A row that is never drawn, used to search lines without caching them
+    pub fn plain(text: String) -> Row {
         Row {
-            text: String::new(),
+            text,
             rendered: String::new(),
             highlight: Vec::new(),
+            start: Open::default(),
+            open: Open::default(),
         }
     }
 
-    pub fn from(text: String, syntax: &mut Syntax) -> Row {
+    // start is the comment or string left open by the row before
+    pub fn from(text: String, syntax: &Syntax, start: Open) -> Row {
         let mut row = Row {
             text,
             rendered: String::new(),
             highlight: Vec::new(),
+            start: Open::default(),
+            open: Open::default(),
         };
         row.render();
-        row.update_highlight(syntax);
+        row.update_highlight(syntax, start);
         row
     }
 
+    // Length of the row in grapheme clusters. In ASCII text, which long rows
+    // usually are, every byte is a grapheme, so that is counted without
+    // segmenting the row.
     pub fn len(&self) -> usize {
-        self.text.len()
+        if self.text.is_ascii() {
+            return self.text.len();
+        }
+        self.text.graphemes(true).count()
     }
-    pub fn get(&self, low: usize, high: usize) -> &str {
-        if low >= self.len() {
-            &""
-        } else if high >= self.len() {
-            &self.text[low..self.len()]
-        } else {
-            &self.text[low..high]
+    // Byte offset of the grapheme at index, or the end of the text if index is past it
+    pub fn byte_index(&self, index: usize) -> usize {
+        if self.text.is_ascii() {
+            return std::cmp::min(index, self.text.len());
+        }
+        match self.text.grapheme_indices(true).nth(index) {
+            Some((i, _)) => i,
+            None => self.text.len(),
+        }
+    }
+    // Index of the grapheme starting at byte offset at, rounding up when at falls
+    // inside a grapheme
+    pub fn grapheme_index(&self, at: usize) -> usize {
+        if self.text.is_ascii() {
+            return std::cmp::min(at, self.text.len());
         }
+        self.text
+            .grapheme_indices(true)
+            .take_while(|(i, _)| *i < at)
+            .count()
     }
-    pub fn get_render_slice(&self, low: usize, high: usize) -> &str {
-        if low >= self.len() {
-            &""
-        } else if high >= self.len() {
-            &self.rendered[low..self.len()]
+    pub fn get(&self, low: usize, high: usize) -> &str {
+        if low >= high {
+            ""
         } else {
-            &self.rendered[low..high]
+            &self.text[self.byte_index(low)..self.byte_index(high)]
         }
     }
     pub fn get_render(&self) -> &str {
         &self.rendered
     }
+    // Index of the first grapheme at or after start where query begins
+    fn find_from(&self, start: usize, query: &str) -> Option<usize> {
+        self.text
+            .grapheme_indices(true)
+            .enumerate()
+            .skip(start)
+            .find(|(_, (i, _))| self.text[*i..].starts_with(query))
+            .map(|(index, _)| index)
+    }
     pub fn search(&self, query: &str) -> Option<usize> {
-        return self.text.find(query);
+        self.find_from(0, query)
     }
     //search string after given index
     pub fn search_from(&self, index: usize, query: &str) -> Option<usize> {
-        if let Some(res_index) = self.get(index + 1, self.len()).find(query) {
-            Some(index + res_index + 1)
-        } else {
-            None
-        }
+        self.find_from(index + 1, query)
     }
-    // pub fn search_to(&self, index: usize, query: &str) -> Option<usize> {
-    //     return self.get(0, index + query.len()).find(query);
-    // }
     pub fn search_reverse(&self, query: &str) -> Option<usize> {
-        if self.len() < query.len() {
-            return None;
-        };
-        for i in (0..=(self.len() - query.len())).rev() {
-            if self.get(i, i + query.len()) == query {
-                return Some(i);
-            }
-        }
-        None
+        self.search_reverse_to(self.len(), query)
     }
     // Search row for query up to but not including index as a starting position for string
     pub fn search_reverse_to(&self, index: usize, query: &str) -> Option<usize> {
-        for i in (0..index).rev() {
-            if self.get(i, i + query.len()) == query {
-                return Some(i);
-            }
-        }
-        None
-    }
-    pub fn split_off(&mut self, index: usize, syntax: &mut Syntax) -> Row {
-        let next_text = self.text.split_off(index);
-        self.render();
-        Row::from(next_text, syntax)
+        let starts: Vec<usize> = self
+            .text
+            .grapheme_indices(true)
+            .take(index)
+            .map(|(i, _)| i)
+            .collect();
+        (0..starts.len())
+            .rev()
+            .find(|&g| self.text[starts[g]..].starts_with(query))
     }
     pub fn render(&mut self) {
-        let mut rendered = String::new();
-        for c in self.text.chars() {
-            if c == '\t' {
-                for _ in 0..TAB_STOP {
+        let mut rendered = String::with_capacity(self.text.len());
+        let mut rx = 0;
+        for g in self.text.graphemes(true) {
+            if g == "\t" {
+                rendered.push(' ');
+                rx += 1;
+                while rx % TAB_STOP != 0 {
                     rendered.push(' ');
+                    rx += 1;
                 }
             } else {
-                rendered.push(c);
+                rendered.push_str(g);
+                rx += grapheme_width(g);
             }
         }
         self.rendered = rendered;
     }
-    pub fn insert(&mut self, index: usize, c: char, syntax: &mut Syntax) {
-        self.text.insert(index, c);
-        self.render();
-        self.update_highlight(syntax);
-    }
-    pub fn join(&mut self, new: &Row) {
-        self.text.push_str(&new.text);
-        self.rendered.push_str(&new.rendered);
-    }
-    pub fn remove(&mut self, index: usize, syntax: &mut Syntax) {
-        self.text.remove(index);
-        self.render();
-        self.update_highlight(syntax)
-    }
-    pub fn cx_to_rx(&self, cx: usize) -> usize {
-        let mut rx: usize = 0;
-        let end = std::cmp::min(cx, self.len());
-        for c in self.text[..end].chars() {
-            if c == '\t' {
-                rx += (TAB_STOP - 1) - (rx % TAB_STOP);
+    // Returns the display column and the char index into rendered of grapheme cx
+    fn cx_to_offsets(&self, cx: usize) -> (usize, usize) {
+        let mut rx = 0;
+        let mut render_index = 0;
+        for g in self.text.graphemes(true).take(cx) {
+            if g == "\t" {
+                let width = TAB_STOP - (rx % TAB_STOP);
+                rx += width;
+                render_index += width;
+            } else {
+                rx += grapheme_width(g);
+                render_index += g.chars().count();
             }
-            rx += 1;
         }
-        rx
+        (rx, render_index)
     }
-    pub fn update_highlight(&mut self, syntax: &mut Syntax) {
-        let mut prev_was_separator = true;
-        let mut in_string = None;
-
-        if self.highlight.len() < self.rendered.len() {
-            self.highlight
-                .resize(self.rendered.len(), Highlight::Normal);
-        }
-        let mut num_to_skip = 0;
-        for (i, c) in self.rendered.chars().enumerate() {
-            if num_to_skip != 0 {
-                num_to_skip -= 1;
-                continue;
-            }
-            if syntax.slcs_len() != 0 && in_string == None {
-                if self.get_render_slice(i, i + syntax.slcs_len()) == syntax.get_slcs() {
-                    for j in i..self.rendered.len() {
-                        self.highlight[j] = Highlight::Comment;
-                    }
-                    break;
-                }
-            }
-            let prev_hl = if i == 0 {
-                Highlight::Normal
+    pub fn cx_to_rx(&self, cx: usize) -> usize {
+        self.cx_to_offsets(cx).0
+    }
+    // Index of the grapheme drawn at display column rx, or the length of the row
+    // if rx is past its end
+    pub fn rx_to_cx(&self, rx: usize) -> usize {
+        let mut col = 0;
+        for (cx, g) in self.text.graphemes(true).enumerate() {
+            col += if g == "\t" {
+                TAB_STOP - (col % TAB_STOP)
             } else {
-                self.highlight[i - 1].clone()
+                grapheme_width(g)
             };
-            if syntax.get_flags() & HIGHLIGHT_STRINGS != 0 {
-                if let Some(quote) = in_string {
-                    // if let Some(quote) = syntax.in_string() {
-                    self.highlight[i] = Highlight::Str;
-                    if c == '\\' && i + 1 < self.rendered.len() {
-                        self.highlight[i + 1] = Highlight::Str;
-                        num_to_skip = 1;
-                        continue;
-                    }
-                    if c == quote {
-                        // syntax.set_in_string(None);
-                        in_string = None;
-                    }
-                    prev_was_separator = true;
-                    continue;
-                } else {
-                    if c == '"' || (syntax.get_flags() & ALLOW_SINGLE_QUOTE != 0 && c == '\'') {
-                        // syntax.set_in_string(Some(c));
-                        in_string = Some(c);
-                        self.highlight[i] = Highlight::Str;
-                        continue;
-                    }
-                }
+            if col > rx {
+                return cx;
             }
-            if syntax.get_flags() & HIGHLIGHT_NUMBERS != 0 {
-                if c.is_numeric() && (prev_was_separator || prev_hl == Highlight::Number)
-                    || (c == '.' && prev_hl == Highlight::Number)
-                {
-                    self.highlight[i] = Highlight::Number;
-                    continue;
-                }
+        }
+        self.len()
+    }
+    // Display columns where each screen line starts when the row is wrapped to
+    // width columns, and to rest columns after the first line. Lines break after
+    // whitespace where the word that follows fits on the next line.
+    pub fn wrap(&self, width: usize, rest: usize) -> Vec<usize> {
+        let mut starts = vec![0];
+        let mut start = 0;
+        let mut limit = width;
+        let mut col = 0;
+        // Column after the last whitespace on the current line
+        let mut word_start = 0;
+        for g in self.rendered.graphemes(true) {
+            let w = grapheme_width(g);
+            // A grapheme wider than the screen gets a line to itself
+            if col + w > start + limit && col > start {
+                start = if word_start > start && col + w - word_start <= rest {
+                    word_start
+                } else {
+                    col
+                };
+                starts.push(start);
+                limit = rest;
             }
-            if prev_was_separator {
-                let mut found = false;
-                for word in syntax.get_keywords() {
-                    let kw2 = word.ends_with("|");
-                    let len = if kw2 { word.len() - 1 } else { word.len() };
-                    let followed_by_sep = self.rendered.len() <= i + len - 1
-                        || is_separator_or_none(self.rendered[i + len..].chars().next());
-                    if followed_by_sep && &word[..len] == self.get_render_slice(i, i + len) {
-                        let new_hl = if kw2 {
-                            Highlight::Keyword2
-                        } else {
-                            Highlight::Keyword1
-                        };
-                        for j in i..(i + len) {
-                            if self.highlight.len() <= j {
-                                self.highlight.push(new_hl.clone());
-                            } else {
-                                self.highlight[j] = new_hl.clone();
-                            }
-                        }
-                        num_to_skip = len;
-                        found = true;
-                        break;
-                    }
-                }
-                if found {
-                    prev_was_separator = false;
-                    continue;
-                }
+            col += w;
+            if g.chars().all(char::is_whitespace) {
+                word_start = col;
             }
-            self.highlight[i] = Highlight::Normal;
-            prev_was_separator = is_separator(c);
         }
+        // The cursor after the end of a row that fills its last line goes on the next
+        if col >= start + limit {
+            starts.push(col);
+        }
+        starts
+    }
+    // Converts a grapheme range of text into a char range of rendered
+    fn render_range(&self, start: usize, distance: usize) -> (usize, usize) {
+        let low = self.cx_to_offsets(start).1;
+        let high = self.cx_to_offsets(start + distance).1;
+        (low, high - low)
+    }
+    // Highlights the row, starting inside the comment or string left open by the
+    // row before
+    pub fn update_highlight(&mut self, syntax: &Syntax, start: Open) {
+        let mut end = std::cmp::min(self.rendered.len(), MAX_HIGHLIGHT_LEN);
+        while !self.rendered.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (highlight, open) = syntax.highlight_line(&self.rendered[..end], &start);
+        self.highlight = highlight;
+        self.start = start;
+        self.open = open;
+    }
+    pub fn start(&self) -> &Open {
+        &self.start
+    }
+    pub fn open(&self) -> Open {
+        self.open.clone()
     }
     pub fn get_text(&self) -> &str {
         &self.text
     }
-    pub fn get_highlight_at(&self, index: usize) -> &Highlight {
+    // index is a char index into the rendered text
+    pub fn get_highlight_at(&self, index: usize) -> Highlight {
         if index >= self.highlight.len() {
-            return &Highlight::Normal;
+            return Highlight::NORMAL;
         }
-        &self.highlight[index]
+        self.highlight[index]
     }
+    // start and distance are measured in graphemes of text
     pub fn set_highlight_from(&mut self, highlight: Highlight, start: usize, distance: usize) {
-        for i in start..(start + distance) {
-            if i >= self.highlight.len() {
-                return;
-            };
-            self.highlight[i] = highlight.clone();
+        let (start, distance) = self.render_range(start, distance);
+        if self.highlight.len() < start + distance {
+            // Past the highlighted part of a long row
+            self.highlight.resize(start + distance, Highlight::NORMAL);
+        }
+        for h in self.highlight[start..start + distance].iter_mut() {
+            *h = highlight;
         }
     }
     pub fn save_highlight(&self, start: usize, distance: usize) -> Option<Vec<Highlight>> {
-        if start >= self.highlight.len() {
+        let (start, distance) = self.render_range(start, distance);
+        if start >= self.rendered.chars().count() {
             return None;
         }
-        let mut saved = vec![];
-        for i in start..(start + distance) {
-            if i >= self.highlight.len() {
-                break;
-            }
-            saved.push(self.highlight[i].clone());
-        }
-        Some(saved)
+        Some(
+            (start..start + distance)
+                .map(|i| self.get_highlight_at(i))
+                .collect(),
+        )
     }
-    pub fn set_highlight_group(&mut self, start: usize, highlights: &Vec<Highlight>) {
+    pub fn set_highlight_group(&mut self, start: usize, highlights: &[Highlight]) {
+        let start = self.cx_to_offsets(start).1;
         if start >= self.highlight.len() {
             return;
         }
@@ -274,7 +281,331 @@ impl Row {
             if i >= self.highlight.len() {
                 return;
             }
-            self.highlight[i] = highlights[i - start].clone();
+            self.highlight[i] = highlights[i - start];
+        }
+    }
+}
+
+// Rendered and highlighted rows, keyed by line number. Only the rows around the
+// viewport are kept, the text itself lives in the Buffer.
+// It also remembers what each line leaves open for the next, from the top of the
+// file down to the last line highlighted, since a row can't be highlighted
+// without knowing what it starts in. Edits don't highlight the lines after them
+// again right away, they mark the lines whose end may have changed dirty: the
+// edited ones, and those after a line that now ends differently. Each end was
+// found from the end of the line before unless the line is dirty, so every line
+// above the first dirty one is up to date.
+pub struct RowCache {
+    rows: BTreeMap<usize, Row>,
+    ends: Vec<Open>,
+    dirty: BTreeSet<usize>,
+}
+
+impl RowCache {
+    pub fn new() -> RowCache {
+        RowCache {
+            rows: BTreeMap::new(),
+            ends: Vec::new(),
+            dirty: BTreeSet::new(),
         }
     }
+    pub fn get(&self, y: usize) -> Option<&Row> {
+        self.rows.get(&y)
+    }
+    pub fn get_mut(&mut self, y: usize) -> Option<&mut Row> {
+        self.rows.get_mut(&y)
+    }
+    pub fn insert(&mut self, y: usize, row: Row) {
+        self.rows.insert(y, row);
+    }
+    // Drops row y after its text changed, so it is rebuilt from the buffer the
+    // next time it is needed, and marks its end dirty
+    pub fn changed(&mut self, y: usize) {
+        self.rows.remove(&y);
+        self.mark_dirty(y);
+    }
+    fn mark_dirty(&mut self, y: usize) {
+        if y < self.ends.len() {
+            self.dirty.insert(y);
+        }
+    }
+    // What line y starts in, if the lines before it are up to date
+    pub fn open_before(&self, y: usize) -> Option<Open> {
+        if y == 0 {
+            return Some(Open::top());
+        }
+        if y > self.next_to_highlight() {
+            return None;
+        }
+        self.ends.get(y - 1).cloned()
+    }
+    // The first line whose end is dirty or not known yet
+    pub fn next_to_highlight(&self) -> usize {
+        match self.dirty.iter().next() {
+            Some(y) => *y,
+            None => self.ends.len(),
+        }
+    }
+    // Whether edits left lines to highlight again
+    pub fn has_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+    // Records what line y leaves open, found from the end of the line before.
+    // If that changed, the line after it is dirty now.
+    pub fn set_end(&mut self, y: usize, open: Open) {
+        self.dirty.remove(&y);
+        if y == self.ends.len() {
+            self.ends.push(open);
+        } else if self.ends[y] != open {
+            self.ends[y] = open;
+            self.mark_dirty(y + 1);
+        }
+    }
+    // Moves the dirty marks at or after y by one line, up or down
+    fn shift_dirty(&mut self, y: usize, down: bool) {
+        let moved = self.dirty.split_off(&y);
+        self.dirty
+            .extend(moved.into_iter().map(|i| if down { i + 1 } else { i - 1 }));
+    }
+    // Renumbers the rows after a line was inserted at y. Until it is highlighted
+    // the new line is taken to leave open what the line before it did, which is
+    // what the lines after it were highlighted with.
+    pub fn insert_line(&mut self, y: usize) {
+        let moved = self.rows.split_off(&y);
+        self.rows
+            .extend(moved.into_iter().map(|(i, row)| (i + 1, row)));
+        if y <= self.ends.len() {
+            let end = match y {
+                0 => Open::top(),
+                _ => self.ends[y - 1].clone(),
+            };
+            self.ends.insert(y, end);
+            self.shift_dirty(y, true);
+            self.mark_dirty(y);
+        }
+    }
+    // Drops row y and renumbers the rows after it. The line after it is dirty
+    // unless it started in what the line before the removed one leaves open.
+    pub fn remove_line(&mut self, y: usize) {
+        let moved = self.rows.split_off(&(y + 1));
+        self.rows.remove(&y);
+        self.rows
+            .extend(moved.into_iter().map(|(i, row)| (i - 1, row)));
+        if y < self.ends.len() {
+            let removed = self.ends.remove(y);
+            self.dirty.remove(&y);
+            self.shift_dirty(y + 1, false);
+            let start = match y {
+                0 => Open::top(),
+                _ => self.ends[y - 1].clone(),
+            };
+            if removed != start {
+                self.mark_dirty(y);
+            }
+        }
+    }
+    // Drops every row that keep returns false for
+    pub fn retain<F: Fn(usize) -> bool>(&mut self, keep: F) {
+        self.rows.retain(|y, _| keep(*y));
+    }
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.ends.clear();
+        self.dirty.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(text: &str, width: usize, rest: usize) -> Vec<usize> {
+        Row::from(String::from(text), &Syntax::new(), Open::default()).wrap(width, rest)
+    }
+
+    #[test]
+    fn wrap_at_words() {
+        assert_eq!(wrap("", 10, 8), vec![0]);
+        assert_eq!(wrap("short", 10, 8), vec![0]);
+        // Breaks after the space, the next lines are narrower
+        assert_eq!(wrap("hello world again", 10, 8), vec![0, 6, 12]);
+        // A word too long for a line is broken where the line ends
+        assert_eq!(wrap("abcdefghijklmnop", 10, 4), vec![0, 10, 14]);
+        // The cursor after a row that fills its last line goes on a new one
+        assert_eq!(wrap("abcdefghij", 10, 8), vec![0, 10]);
+    }
+
+    #[test]
+    fn wrap_wide_graphemes() {
+        // A wide grapheme that would straddle the edge starts the next line
+        assert_eq!(wrap("abc漢字", 6, 6), vec![0, 5]);
+        // One wider than the screen gets a line to itself
+        assert_eq!(wrap("a漢b", 1, 1), vec![0, 1, 3, 4]);
+        // Tabs are expanded before wrapping
+        assert_eq!(wrap("\tx", 4, 4), vec![0, 4]);
+    }
+
+    // What a line of C that opens a comment leaves open
+    fn in_comment() -> Open {
+        let c = Syntax::builtin()
+            .into_iter()
+            .find(|syntax| syntax.matches("a.c"))
+            .unwrap();
+        assert!(c.spans_lines());
+        c.highlight_line("/*", &Open::default()).1
+    }
+
+    fn cache(lines: usize) -> RowCache {
+        let mut rows = RowCache::new();
+        for y in 0..lines {
+            rows.set_end(y, Open::default());
+        }
+        rows
+    }
+
+    fn dirty(rows: &RowCache) -> Vec<usize> {
+        rows.dirty.iter().copied().collect()
+    }
+
+    #[test]
+    fn ends_changing_dirty_the_next_line() {
+        let mut rows = cache(5);
+        assert_eq!(rows.next_to_highlight(), 5);
+        assert_eq!(rows.open_before(0), Some(Open::top()));
+        assert_eq!(rows.open_before(5), Some(Open::default()));
+        assert_eq!(rows.open_before(6), None);
+
+        rows.changed(1);
+        assert_eq!(dirty(&rows), vec![1]);
+        assert_eq!(rows.open_before(2), None);
+        // Line 1 still ends the same way, so nothing after it changes
+        rows.set_end(1, Open::default());
+        assert!(!rows.has_dirty());
+
+        rows.changed(1);
+        rows.set_end(1, in_comment());
+        assert_eq!(dirty(&rows), vec![2]);
+        assert_eq!(rows.open_before(2), Some(in_comment()));
+        rows.set_end(2, in_comment());
+        rows.set_end(3, in_comment());
+        rows.set_end(4, in_comment());
+        assert!(!rows.has_dirty());
+
+        // Lines not highlighted yet are never dirty
+        rows.changed(7);
+        assert!(!rows.has_dirty());
+    }
+
+    #[test]
+    fn inserted_lines_are_dirty() {
+        let mut rows = cache(4);
+        rows.set_end(1, in_comment());
+        rows.set_end(2, in_comment());
+        rows.changed(3);
+        rows.insert(3, Row::plain(String::from("three")));
+
+        rows.insert_line(2);
+        assert_eq!(rows.ends.len(), 5);
+        // The new line takes the end of the one before it until it is highlighted
+        assert_eq!(rows.ends[2], in_comment());
+        assert_eq!(dirty(&rows), vec![2, 4]);
+        assert_eq!(rows.get(4).map(Row::get_text), Some("three"));
+        assert!(rows.get(3).is_none());
+
+        rows.insert_line(0);
+        assert_eq!(rows.ends[0], Open::top());
+        assert_eq!(dirty(&rows), vec![0, 3, 5]);
+
+        // Past the lines highlighted so far nothing is known to be dirty
+        rows.insert_line(7);
+        assert_eq!(rows.ends.len(), 6);
+    }
+
+    #[test]
+    fn removed_lines_dirty_the_next_if_it_started_differently() {
+        let mut rows = cache(6);
+        rows.set_end(2, in_comment());
+        rows.set_end(3, in_comment());
+        rows.set_end(4, Open::default());
+        rows.changed(5);
+        rows.insert(4, Row::plain(String::from("four")));
+
+        // Line 4 started in the comment line 3 left open, and still does
+        rows.remove_line(3);
+        assert_eq!(dirty(&rows), vec![4]);
+        assert_eq!(rows.get(3).map(Row::get_text), Some("four"));
+
+        // Now it starts after line 1, which leaves nothing open
+        rows.remove_line(2);
+        assert_eq!(dirty(&rows), vec![2, 3]);
+        assert_eq!(rows.ends.len(), 4);
+
+        rows.remove_line(3);
+        assert_eq!(dirty(&rows), vec![2]);
+    }
+
+    #[test]
+    fn long_rows_pass_on_what_their_start_left_open() {
+        let c = Syntax::builtin()
+            .into_iter()
+            .find(|syntax| syntax.matches("a.c"))
+            .unwrap();
+        let short = Row::from(String::from("x /* y"), &c, Open::default());
+        assert_eq!(short.open(), in_comment());
+        let long = format!("x /* {}", "y".repeat(MAX_HIGHLIGHT_LEN));
+        let long = Row::from(long, &c, Open::default());
+        assert_eq!(long.open(), in_comment());
+        assert_eq!(
+            long.get_highlight_at(MAX_HIGHLIGHT_LEN + 1),
+            Highlight::NORMAL
+        );
+        let inside = Row::from("y".repeat(MAX_HIGHLIGHT_LEN + 1), &c, in_comment());
+        assert_eq!(inside.open(), in_comment());
+        // Closed past the highlighted prefix, which goes unseen
+        let closed = format!("x /* {} */", "y".repeat(MAX_HIGHLIGHT_LEN));
+        assert_eq!(Row::from(closed, &c, Open::default()).open(), in_comment());
+    }
+
+    fn row(text: &str) -> Row {
+        Row::from(String::from(text), &Syntax::new(), Open::default())
+    }
+
+    #[test]
+    fn graphemes_and_columns() {
+        // A wide char, e with a combining acute accent and a tab
+        let row = row("a漢e\u{301}\tb");
+        assert_eq!(row.len(), 5);
+        assert_eq!(row.get_render(), "a漢e\u{301}    b");
+        let bytes: Vec<usize> = (0..7).map(|cx| row.byte_index(cx)).collect();
+        assert_eq!(bytes, vec![0, 1, 4, 7, 8, 9, 9]);
+        // Offsets inside a grapheme round up to the next one
+        let indexes: Vec<usize> = (0..10).map(|at| row.grapheme_index(at)).collect();
+        assert_eq!(indexes, vec![0, 1, 2, 2, 2, 3, 3, 3, 4, 5]);
+        assert_eq!(row.get(1, 3), "漢e\u{301}");
+
+        let columns: Vec<usize> = (0..6).map(|cx| row.cx_to_rx(cx)).collect();
+        assert_eq!(columns, vec![0, 1, 3, 4, 8, 9]);
+        // Columns covered by a wide char or a tab belong to it
+        let cxs: Vec<usize> = [0, 1, 2, 3, 4, 7, 8, 9, 100]
+            .iter()
+            .map(|rx| row.rx_to_cx(*rx))
+            .collect();
+        assert_eq!(cxs, vec![0, 1, 1, 2, 3, 3, 4, 5, 5]);
+    }
+
+    #[test]
+    fn search_by_grapheme() {
+        let cjk = row("漢字 漢字");
+        assert_eq!(cjk.search("字"), Some(1));
+        assert_eq!(cjk.search_from(1, "字"), Some(4));
+        assert_eq!(cjk.search_from(4, "字"), None);
+        assert_eq!(cjk.search_reverse("漢"), Some(3));
+        assert_eq!(cjk.search_reverse_to(3, "漢"), Some(0));
+        assert_eq!(cjk.search("x"), None);
+
+        let accented = row("\tcafe\u{301} caf");
+        assert_eq!(accented.search("e\u{301}"), Some(4));
+        assert_eq!(accented.search_from(1, "caf"), Some(6));
+        assert_eq!(accented.search_reverse_to(6, "caf"), Some(1));
+    }
 }
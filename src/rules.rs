@@ -0,0 +1,299 @@
+// *** HIGHLIGHT RULES ***
+// Lines are highlighted by rules: regular expressions tried at each position,
+// where the rule matching earliest wins and the rule listed first breaks ties.
+// Named captures like (?P<function>...) highlight their part of the match with
+// the group of that name. Rules belong to contexts, and a rule can enter another
+// context, whose rules then apply until one of them leaves it again, even lines
+// later. Comments, strings and attributes that span lines work that way.
+use crate::syntax::Highlight;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Stay,
+    // Enters the context at this index
+    Push(usize),
+    // Goes back to the context the current one was entered from
+    Pop,
+}
+
+#[derive(Clone)]
+pub struct Rule {
+    pattern: String,
+    // The compiled pattern, or None if the pattern refers back to the captures
+    // of the match that entered the context, with \1 to \9. Those are compiled
+    // for each text they refer to and kept in compiled. Regexes are shared
+    // rather than cloned, since a clone starts without the search caches.
+    regex: Option<Rc<Regex>>,
+    compiled: RefCell<HashMap<Vec<String>, Rc<Regex>>>,
+    // Highlight of the match, None to keep that of the context
+    highlight: Option<Highlight>,
+    // Highlights of named captures, by capture index
+    captures: Vec<(usize, Highlight)>,
+    action: Action,
+    // Only tried on the first line of the file
+    first_line: bool,
+}
+
+// Replaces \1 to \9 in pattern with the text of those captures
+fn substitute(pattern: &str, captures: &[String]) -> String {
+    let mut result = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(d @ '1'..='9') => {
+                let n = d as usize - '1' as usize;
+                result.push_str(&regex::escape(captures.get(n).map_or("", |s| s.as_str())));
+            }
+            Some(other) => {
+                result.push(c);
+                result.push(other);
+            }
+            None => result.push(c),
+        }
+    }
+    result
+}
+
+impl Rule {
+    pub fn new(
+        pattern: &str,
+        highlight: Option<Highlight>,
+        action: Action,
+    ) -> Result<Rule, String> {
+        // Compiling with empty captures checks the pattern either way
+        let checked = Regex::new(&substitute(pattern, &[])).map_err(|e| {
+            // The last line of the message says what is wrong
+            let message = e.to_string();
+            message.lines().last().unwrap_or("").trim().to_string()
+        })?;
+        let captures = checked
+            .capture_names()
+            .enumerate()
+            .filter_map(|(i, name)| name.map(|name| (i, Highlight::named(name))))
+            .collect();
+        let refers_back = substitute(pattern, &[]) != pattern;
+        Ok(Rule {
+            pattern: String::from(pattern),
+            regex: if refers_back {
+                None
+            } else {
+                Some(Rc::new(checked))
+            },
+            compiled: RefCell::new(HashMap::new()),
+            highlight,
+            captures,
+            action,
+            first_line: false,
+        })
+    }
+
+    pub fn on_first_line(self) -> Rule {
+        Rule {
+            first_line: true,
+            ..self
+        }
+    }
+
+    fn regex(&self, captures: &[String]) -> Rc<Regex> {
+        if let Some(regex) = &self.regex {
+            return Rc::clone(regex);
+        }
+        let mut compiled = self.compiled.borrow_mut();
+        let regex = compiled.entry(captures.to_vec()).or_insert_with(|| {
+            let pattern = substitute(&self.pattern, captures);
+            Rc::new(Regex::new(&pattern).expect("Checked when loaded"))
+        });
+        Rc::clone(regex)
+    }
+
+    fn refers_back(&self) -> bool {
+        self.regex.is_none()
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    name: String,
+    // Highlight of the text no rule matches
+    highlight: Highlight,
+    // Left at the end of the line, like strings that can't span lines
+    one_line: bool,
+    rules: Vec<Rule>,
+}
+
+// What a line leaves open for the next: the contexts it entered and didn't leave
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Open {
+    // Innermost last, each with the text of the captures of the match that
+    // entered it if its rules refer back to them
+    stack: Vec<(usize, Vec<String>)>,
+    // Set for the first line of the file, instead of what the line before left
+    top: bool,
+}
+
+impl Open {
+    pub fn top() -> Open {
+        Open {
+            stack: vec![],
+            top: true,
+        }
+    }
+}
+
+// The contexts of a syntax, starting with the one each file starts in
+#[derive(Clone)]
+pub struct Grammar {
+    contexts: Vec<Context>,
+}
+
+impl Grammar {
+    pub fn new() -> Grammar {
+        let mut grammar = Grammar { contexts: vec![] };
+        grammar.context("main", Highlight::NORMAL, false);
+        grammar
+    }
+
+    // Adds a context and returns its index
+    pub fn context(&mut self, name: &str, highlight: Highlight, one_line: bool) -> usize {
+        self.contexts.push(Context {
+            name: String::from(name),
+            highlight,
+            one_line,
+            rules: vec![],
+        });
+        self.contexts.len() - 1
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.contexts
+            .iter()
+            .position(|context| context.name == name)
+    }
+
+    pub fn rule(&mut self, context: usize, rule: Rule) {
+        self.contexts[context].rules.push(rule);
+    }
+
+    // Whether a line can end inside a context, so that the next line starts in it
+    pub fn spans_lines(&self) -> bool {
+        self.contexts[1..].iter().any(|context| !context.one_line)
+    }
+
+    // Highlights a line starting in what the line before left open, and returns
+    // the highlight of each char and what the line leaves open
+    pub fn highlight(&self, line: &str, start: &Open) -> (Vec<Highlight>, Open) {
+        let mut bytes = vec![Highlight::NORMAL; line.len()];
+        let mut paint = |from: usize, to: usize, highlight: Highlight| {
+            for h in bytes[from..to].iter_mut() {
+                *h = highlight;
+            }
+        };
+        let mut open = Open {
+            stack: start.stack.clone(),
+            top: false,
+        };
+        // Where each rule of each context matches next, None for no match, with
+        // empty matches ignored. A match found further on stays valid as the
+        // position moves up to it, even after leaving the context and coming back,
+        // unless the rule refers back to captures.
+        let mut next: Vec<Vec<Option<Option<usize>>>> = vec![vec![]; self.contexts.len()];
+        let mut pos = 0;
+        loop {
+            let (index, captures) = match open.stack.last() {
+                Some((index, captures)) => (*index, captures.clone()),
+                None => (0, vec![]),
+            };
+            let context = &self.contexts[index];
+            let next = &mut next[index];
+            next.resize(context.rules.len(), None);
+            // The earliest match and the rule it is for
+            let mut best: Option<(usize, usize)> = None;
+            for (k, rule) in context.rules.iter().enumerate() {
+                if rule.first_line && !start.top {
+                    continue;
+                }
+                let found = match next[k] {
+                    Some(found) if found.is_none_or(|from| from >= pos) && !rule.refers_back() => {
+                        found
+                    }
+                    _ => {
+                        let found = rule
+                            .regex(&captures)
+                            .find_at(line, pos)
+                            .filter(|m| !m.is_empty())
+                            .map(|m| m.start());
+                        next[k] = Some(found);
+                        found
+                    }
+                };
+                if let Some(from) = found {
+                    if best.is_none_or(|(best_from, _)| from < best_from) {
+                        best = Some((from, k));
+                    }
+                }
+            }
+            let (from, k) = match best {
+                Some(best) => best,
+                None => {
+                    paint(pos, line.len(), context.highlight);
+                    break;
+                }
+            };
+            paint(pos, from, context.highlight);
+            let rule = &context.rules[k];
+            let (caps, to) = match rule.regex(&captures).captures_at(line, pos) {
+                Some(caps) => {
+                    let to = caps.get(0).map_or(from, |m| m.end());
+                    (caps, to)
+                }
+                None => break,
+            };
+            paint(from, to, rule.highlight.unwrap_or(context.highlight));
+            for (i, highlight) in rule.captures.iter() {
+                if let Some(m) = caps.get(*i) {
+                    paint(m.start(), m.end(), *highlight);
+                }
+            }
+            pos = to;
+            match rule.action {
+                Action::Stay => {}
+                Action::Push(target) => {
+                    let texts = if self.contexts[target].rules.iter().any(Rule::refers_back) {
+                        (1..caps.len())
+                            .map(|i| {
+                                caps.get(i)
+                                    .map_or(String::new(), |m| m.as_str().to_string())
+                            })
+                            .collect()
+                    } else {
+                        vec![]
+                    };
+                    open.stack.push((target, texts));
+                }
+                Action::Pop => {
+                    open.stack.pop();
+                }
+            }
+            if pos >= line.len() {
+                break;
+            }
+        }
+        while open
+            .stack
+            .last()
+            .is_some_and(|(index, _)| self.contexts[*index].one_line)
+        {
+            open.stack.pop();
+        }
+        let highlight = line.char_indices().map(|(i, _)| bytes[i]).collect();
+        (highlight, open)
+    }
+}
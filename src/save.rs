@@ -0,0 +1,253 @@
+// *** SAVING ***
+// Files are written to a temporary file beside the original, which then replaces
+// it with a rename. Either the old or the new contents are on disk at any
+// moment, even if the editor or the machine dies halfway through a save.
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{fchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// What the file on disk looked like when it was last read or written. If any of
+// these differ later, another program changed or replaced the file.
+#[derive(PartialEq)]
+pub struct DiskState {
+    modified: Option<SystemTime>,
+    size: u64,
+    device: u64,
+    inode: u64,
+}
+
+impl DiskState {
+    pub fn from(metadata: &Metadata) -> DiskState {
+        DiskState {
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        }
+    }
+
+    pub fn read(path: &Path) -> Option<DiskState> {
+        fs::metadata(path)
+            .ok()
+            .map(|metadata| DiskState::from(&metadata))
+    }
+}
+
+// Writes a file through write and moves it into place at path. Returns a warning
+// if the file was written but something is off, or a message describing the
+// step that failed. A file whose owner can't be kept is overwritten in place
+// instead, unless may_overwrite is false because its old contents are still
+// being read, as they are for a mapped file.
+pub fn write_atomically<F>(
+    path: &Path,
+    may_overwrite: bool,
+    write: F,
+) -> Result<Option<String>, String>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    // Saving a symlink replaces the file it points to, not the link itself
+    let target = resolve_symlinks(path);
+    let original = fs::metadata(&target).ok();
+    let name = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let temp_path = target.with_file_name(format!("{}.lock", name));
+    let fail = |action: &str, e: io::Error| {
+        let _ = fs::remove_file(&temp_path);
+        format!("Could not {}: {}", action, e)
+    };
+
+    // A lock file left behind by an earlier crash is truncated rather than appended to
+    let mut temp_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .map_err(|e| format!("Could not create {}: {}", temp_path.display(), e))?;
+    let mut keeps_owner = true;
+    if let Some(original) = &original {
+        // A file owned by someone else can only be replaced by root. Everyone
+        // else overwrites it in place so that its owner stays the same.
+        match fchown(&temp_file, Some(original.uid()), Some(original.gid())) {
+            Ok(()) => {
+                // Set after chown, which may clear setuid bits
+                temp_file
+                    .set_permissions(original.permissions())
+                    .map_err(|e| fail("copy file permissions", e))?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                if !may_overwrite {
+                    return Err(fail("keep file owner without overwriting it in place", e));
+                }
+                keeps_owner = false;
+            }
+            Err(e) => return Err(fail("copy file owner", e)),
+        }
+    }
+    write(&mut temp_file).map_err(|e| fail("write file", e))?;
+    temp_file.sync_all().map_err(|e| fail("sync file", e))?;
+    drop(temp_file);
+    if !keeps_owner {
+        overwrite(&temp_path, &target).map_err(|e| fail("overwrite file", e))?;
+        let _ = fs::remove_file(&temp_path);
+        return Ok(Some(String::from(
+            "written in place to keep its owner, so not atomically",
+        )));
+    }
+    fs::rename(&temp_path, &target).map_err(|e| fail("replace file", e))?;
+    // The rename is only durable once the directory holding the file is synced.
+    // The new contents are in place either way, so that is not a failure.
+    Ok(sync_dir(&target)
+        .err()
+        .map(|e| format!("could not sync directory: {}", e)))
+}
+
+// Fallback for files whose owner can't be copied. This is not atomic, but the
+// new contents are complete before the file is truncated, so a failed write
+// never leaves it empty.
+fn overwrite(temp_path: &Path, target: &Path) -> io::Result<()> {
+    let mut temp_file = File::open(temp_path)?;
+    let mut file = OpenOptions::new().write(true).truncate(true).open(target)?;
+    io::copy(&mut temp_file, &mut file)?;
+    file.sync_all()
+}
+
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    if let Ok(resolved) = fs::canonicalize(path) {
+        return resolved;
+    }
+    // A link to a file that doesn't exist yet is saved by creating its target
+    match fs::read_link(path) {
+        Ok(link) => path.parent().unwrap_or_else(|| Path::new("")).join(link),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+    use std::io::Write;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    // An empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vimacs-save-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn save(path: &Path, contents: &str) -> Result<Option<String>, String> {
+        write_atomically(path, true, |file| file.write_all(contents.as_bytes()))
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn keeps_the_mode() {
+        let dir = temp_dir("mode");
+        let path = dir.join("script.sh");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        assert_eq!(save(&path, "new"), Ok(None));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(mode(&path), 0o751);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_through_symlinks() {
+        let dir = temp_dir("symlink");
+        let (target, link) = (dir.join("target"), dir.join("link"));
+        fs::write(&target, "old").unwrap();
+        symlink("target", &link).unwrap();
+        assert_eq!(save(&link, "new"), Ok(None));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("target"));
+
+        // A dangling link is saved by creating what it points to
+        fs::remove_file(&target).unwrap();
+        assert_eq!(save(&link, "created"), Ok(None));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "created");
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_a_leftover_lock_file() {
+        let dir = temp_dir("lock");
+        let (path, lock) = (dir.join("notes"), dir.join("notes.lock"));
+        fs::write(&path, "old").unwrap();
+        fs::write(&lock, "left behind by a crash, and longer").unwrap();
+        assert_eq!(save(&path, "new"), Ok(None));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!lock.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn creates_new_files() {
+        let dir = temp_dir("new");
+        let path = dir.join("new.txt");
+        assert_eq!(save(&path, "hello\n"), Ok(None));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_keep_the_file() {
+        let dir = temp_dir("failed");
+        let path = dir.join("kept");
+        fs::write(&path, "old").unwrap();
+        let saved = write_atomically(&path, true, |file| {
+            file.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "cannot encode"))
+        });
+        assert_eq!(
+            saved,
+            Err(String::from("Could not write file: cannot encode"))
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overwrites_a_mapped_file_in_place() {
+        let dir = temp_dir("overwrite");
+        let (path, temp_path) = (dir.join("owned"), dir.join("owned.lock"));
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let inode = fs::metadata(&path).unwrap().ino();
+        // The buffer reads the rows it writes from the file being overwritten
+        let mut buffer = Buffer::map(&File::open(&path).unwrap()).unwrap();
+        while buffer.is_indexing() {
+            std::thread::yield_now();
+        }
+        buffer.remove_line(2);
+        buffer.insert(0, 0, "1 ");
+        buffer.write_to(File::create(&temp_path).unwrap()).unwrap();
+        overwrite(&temp_path, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1 one\ntwo\n");
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+// *** SCREEN ***
+// Each frame is drawn into a grid of cells, each holding the grapheme shown there
+// and its style. The grid is compared with the frame that is already on the
+// terminal and only the cells that changed are written, so a keypress usually
+// costs a few bytes instead of a full redraw.
+use crate::row::grapheme_width;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Rewriting this many unchanged cells is shorter than a cursor movement sequence
+const MAX_GAP: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    // One of the 16 standard terminal colors
+    Ansi(u8),
+    // One of the 256 colors of xterm's palette
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    // SGR parameters for this color as the foreground, or the background if
+    // base is 40 instead of 30
+    fn params(self, base: u32) -> String {
+        match self {
+            Color::Default => (base + 9).to_string(),
+            Color::Ansi(n) if n < 8 => (base + n as u32).to_string(),
+            Color::Ansi(n) => (base + 60 + (n as u32 - 8)).to_string(),
+            Color::Indexed(n) => format!("{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub const DEFAULT: Style = Style {
+        fg: Color::Default,
+        bg: Color::Default,
+        bold: false,
+        italic: false,
+        underline: false,
+        reverse: false,
+    };
+
+    // Draws other on top of this style: its colors replace these unless they
+    // are the default, and its attributes are added
+    pub fn overlay(self, other: Style) -> Style {
+        let pick = |mine: Color, theirs: Color| {
+            if theirs == Color::Default {
+                mine
+            } else {
+                theirs
+            }
+        };
+        Style {
+            fg: pick(self.fg, other.fg),
+            bg: pick(self.bg, other.bg),
+            bold: self.bold || other.bold,
+            italic: self.italic || other.italic,
+            underline: self.underline || other.underline,
+            reverse: self.reverse || other.reverse,
+        }
+    }
+
+    // The SGR sequence that changes the terminal from style from to this style
+    fn sgr_from(self, from: Style) -> String {
+        if self == Style::DEFAULT {
+            return String::from("\x1b[m");
+        }
+        let mut params = vec![];
+        let attributes = [
+            (self.bold, from.bold, "1", "22"),
+            (self.italic, from.italic, "3", "23"),
+            (self.underline, from.underline, "4", "24"),
+            (self.reverse, from.reverse, "7", "27"),
+        ];
+        for (on, was_on, set, unset) in attributes.iter() {
+            if on != was_on {
+                params.push(String::from(if *on { *set } else { *unset }));
+            }
+        }
+        if self.fg != from.fg {
+            params.push(self.fg.params(30));
+        }
+        if self.bg != from.bg {
+            params.push(self.bg.params(40));
+        }
+        format!("\x1b[{}m", params.join(";"))
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct Cell {
+    // Empty for the second column of a wide grapheme
+    text: String,
+    style: Style,
+}
+
+impl Cell {
+    fn blank() -> Cell {
+        Cell {
+            text: String::from(" "),
+            style: Style::DEFAULT,
+        }
+    }
+}
+
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Cell>,
+    // The frame on the terminal, or None if its contents are unknown
+    drawn: Option<Vec<Cell>>,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            rows: 0,
+            cols: 0,
+            cells: vec![],
+            drawn: None,
+        }
+    }
+
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        self.rows = rows;
+        self.cols = cols;
+        self.clear(Style::DEFAULT);
+        self.invalidate();
+    }
+
+    // Redraws everything on the next render, for when the terminal was cleared
+    // or written to by someone else
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    // Starts a new frame with every cell blank in the given style
+    pub fn clear(&mut self, style: Style) {
+        self.cells = vec![
+            Cell {
+                text: String::from(" "),
+                style,
+            };
+            self.rows * self.cols
+        ];
+    }
+
+    // Draws a grapheme at column x of row y and returns the column after it.
+    // Graphemes that don't fit at the right edge are cut off.
+    pub fn put(&mut self, x: usize, y: usize, grapheme: &str, style: Style) -> usize {
+        let width = grapheme_width(grapheme);
+        if y >= self.rows || x >= self.cols || width == 0 {
+            return x + width;
+        }
+        let i = y * self.cols + x;
+        if x + width > self.cols {
+            // Half of a wide grapheme would wrap onto the next row
+            self.cells[i] = Cell {
+                text: String::from(" "),
+                style,
+            };
+            return x + width;
+        }
+        self.cells[i] = Cell {
+            text: String::from(grapheme),
+            style,
+        };
+        for cell in self.cells[i + 1..i + width].iter_mut() {
+            *cell = Cell {
+                text: String::new(),
+                style,
+            };
+        }
+        x + width
+    }
+
+    pub fn put_str(&mut self, mut x: usize, y: usize, text: &str, style: Style) -> usize {
+        for g in text.graphemes(true) {
+            if x >= self.cols {
+                break;
+            }
+            x = self.put(x, y, g, style);
+        }
+        x
+    }
+
+    // Returns the output that updates the terminal to the new frame and places
+    // the cursor at (x, y)
+    pub fn render(&mut self, cursor: (usize, usize)) -> String {
+        let mut output = String::from("\x1b[?25l\x1b[m");
+        let drawn = match self.drawn.take() {
+            Some(drawn) => drawn,
+            None => {
+                output.push_str("\x1b[2J");
+                vec![Cell::blank(); self.cells.len()]
+            }
+        };
+        let mut style = Style::DEFAULT;
+        // Where the terminal's cursor is, None when unknown
+        let mut at = None;
+        for y in 0..self.rows {
+            let row = &self.cells[y * self.cols..(y + 1) * self.cols];
+            let old = &drawn[y * self.cols..(y + 1) * self.cols];
+            // Everything from here to the end of the row is blank
+            let blank_from = row
+                .iter()
+                .rposition(|cell| *cell != Cell::blank())
+                .map_or(0, |x| x + 1);
+            let mut x = 0;
+            while x < self.cols {
+                if row[x] == old[x] || row[x].text.is_empty() {
+                    x += 1;
+                    continue;
+                }
+                match at {
+                    Some((ax, ay)) if ay == y && ax <= x && x - ax <= MAX_GAP => {
+                        // Write the unchanged cells in between instead of moving
+                        for cell in row[ax..x].iter().filter(|cell| !cell.text.is_empty()) {
+                            if cell.style != style {
+                                output.push_str(&cell.style.sgr_from(style));
+                                style = cell.style;
+                            }
+                            output.push_str(&cell.text);
+                        }
+                    }
+                    _ => output.push_str(&format!("\x1b[{};{}H", y + 1, x + 1)),
+                }
+                if x >= blank_from {
+                    if style != Style::DEFAULT {
+                        output.push_str("\x1b[m");
+                        style = Style::DEFAULT;
+                    }
+                    // Erase the rest of the row
+                    output.push_str("\x1b[K");
+                    at = Some((x, y));
+                    break;
+                }
+                let cell = &row[x];
+                if cell.style != style {
+                    output.push_str(&cell.style.sgr_from(style));
+                    style = cell.style;
+                }
+                output.push_str(&cell.text);
+                x += std::cmp::max(grapheme_width(&cell.text), 1);
+                // Writing the last column leaves the cursor waiting to wrap
+                at = if x < self.cols { Some((x, y)) } else { None };
+            }
+        }
+        if style != Style::DEFAULT {
+            output.push_str("\x1b[m");
+        }
+        output.push_str(&format!("\x1b[{};{}H\x1b[?25h", cursor.1 + 1, cursor.0 + 1));
+        self.drawn = Some(self.cells.clone());
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIDE: &str = "\x1b[?25l\x1b[m";
+
+    fn screen(rows: usize, cols: usize) -> Screen {
+        let mut screen = Screen::new();
+        screen.resize(rows, cols);
+        screen
+    }
+
+    #[test]
+    fn first_frame_redraws() {
+        let mut screen = screen(2, 6);
+        screen.put_str(0, 0, "hi", Style::DEFAULT);
+        assert_eq!(
+            screen.render((2, 0)),
+            format!("{}\x1b[2J\x1b[1;1Hhi\x1b[1;3H\x1b[?25h", HIDE)
+        );
+    }
+
+    #[test]
+    fn only_changes_are_written() {
+        let mut screen = screen(2, 10);
+        screen.put_str(0, 0, "abcdefghij", Style::DEFAULT);
+        screen.render((0, 0));
+
+        // Nothing changed
+        screen.put_str(0, 0, "abcdefghij", Style::DEFAULT);
+        assert_eq!(screen.render((0, 0)), format!("{}\x1b[1;1H\x1b[?25h", HIDE));
+
+        // A small gap is rewritten, a large one is skipped with a cursor movement
+        screen.put_str(0, 0, "Abc", Style::DEFAULT);
+        screen.put(3, 0, "D", Style::DEFAULT);
+        screen.put(9, 0, "J", Style::DEFAULT);
+        assert_eq!(
+            screen.render((0, 1)),
+            format!("{}\x1b[1;1HAbcD\x1b[1;10HJ\x1b[2;1H\x1b[?25h", HIDE)
+        );
+    }
+
+    #[test]
+    fn blank_tails_are_erased() {
+        let mut screen = screen(1, 10);
+        screen.put_str(0, 0, "abcdefgh", Style::DEFAULT);
+        screen.render((0, 0));
+        screen.clear(Style::DEFAULT);
+        screen.put_str(0, 0, "ab", Style::DEFAULT);
+        assert_eq!(
+            screen.render((0, 0)),
+            format!("{}\x1b[1;3H\x1b[K\x1b[1;1H\x1b[?25h", HIDE)
+        );
+    }
+
+    #[test]
+    fn styles_and_wide_graphemes() {
+        let mut screen = screen(1, 4);
+        screen.render((0, 0));
+        let bold = Style {
+            bold: true,
+            fg: Color::Ansi(9),
+            ..Style::DEFAULT
+        };
+        assert_eq!(screen.put(0, 0, "漢", bold), 2);
+        // Half of it would be past the right edge
+        assert_eq!(screen.put(3, 0, "字", Style::DEFAULT), 5);
+        assert_eq!(
+            screen.render((0, 0)),
+            format!("{}\x1b[1;1H\x1b[1;91m漢\x1b[m\x1b[1;1H\x1b[?25h", HIDE)
+        );
+        assert_eq!(
+            Style {
+                bg: Color::Rgb(1, 2, 3),
+                ..Style::DEFAULT
+            }
+            .sgr_from(bold),
+            "\x1b[22;39;48;2;1;2;3m"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// *** SWAP FILE ***
+// While a file is open, a swap file beside it records which process is editing
+// it and, once there are unsaved changes, a copy of the buffer. If the editor
+// dies the swap file is left behind and the changes can be recovered from it.
+//
+// The swap file holds a few header lines followed by the text of the buffer,
+// in UTF-8 with '\n' line endings whatever the format of the file itself.
+use crate::buffer::Buffer;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+const SWAP_HEADER: &str = "vimacs-swap 1";
+
+pub struct SwapFile {
+    pub pid: u32,
+    pub cursor: (usize, usize),
+    // The unsaved text, or None if the buffer had no changes when it was written
+    pub text: Option<Vec<u8>>,
+    pub modified: SystemTime,
+}
+
+impl SwapFile {
+    // Returns None if there is no swap file at path or it is malformed
+    pub fn read(path: &Path) -> Option<SwapFile> {
+        let contents = fs::read(path).ok()?;
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        let mut parts = contents.splitn(5, |b| *b == b'\n');
+        let mut header = || std::str::from_utf8(parts.next()?).ok().map(String::from);
+        if header()? != SWAP_HEADER {
+            return None;
+        }
+        let pid = header()?.strip_prefix("pid ")?.parse().ok()?;
+        let cursor = header()?;
+        let mut cursor = cursor.strip_prefix("cursor ")?.split(' ');
+        let cursor = (cursor.next()?.parse().ok()?, cursor.next()?.parse().ok()?);
+        let dirty = match header()?.as_str() {
+            "state dirty" => true,
+            "state clean" => false,
+            _ => return None,
+        };
+        let text = if dirty {
+            Some(parts.next().unwrap_or(b"").to_vec())
+        } else {
+            None
+        };
+        Some(SwapFile {
+            pid,
+            cursor,
+            text,
+            modified,
+        })
+    }
+
+    // Records that this process is editing the file, along with the buffer if it
+    // has unsaved changes. The swap file is written beside its old version and
+    // renamed over it, so a reader never sees half of it. It is not synced: it is
+    // rewritten every few seconds and only has to outlive the editor, not the
+    // machine.
+    pub fn write(
+        path: &Path,
+        cursor: (usize, usize),
+        buffer: Option<&Buffer>,
+    ) -> Result<(), String> {
+        let temp_path = path.with_extension("swp.tmp");
+        let written = File::create(&temp_path).and_then(|file| {
+            let mut file = BufWriter::new(file);
+            let state = if buffer.is_some() { "dirty" } else { "clean" };
+            write!(
+                file,
+                "{}\npid {}\ncursor {} {}\nstate {}\n",
+                SWAP_HEADER,
+                std::process::id(),
+                cursor.0,
+                cursor.1,
+                state
+            )?;
+            if let Some(buffer) = buffer {
+                for chunk in buffer.chunks() {
+                    file.write_all(chunk)?;
+                }
+            }
+            file.flush()?;
+            fs::rename(&temp_path, path)
+        });
+        written.map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            e.to_string()
+        })
+    }
+
+    // Whether the process that wrote the swap file is still running
+    pub fn is_owner_alive(&self) -> bool {
+        if self.pid == std::process::id() {
+            return false;
+        }
+        let result = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn swap_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vimacs-swap-{}-{}.swp", std::process::id(), name))
+    }
+
+    fn swap_file(pid: u32) -> SwapFile {
+        SwapFile {
+            pid,
+            cursor: (0, 0),
+            text: None,
+            modified: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = swap_path("round-trip");
+        let mut buffer = Buffer::from_bytes(b"one\r\ntwo\r\n", None).unwrap();
+        buffer.insert(1, 3, " 漢");
+        SwapFile::write(&path, (3, 1), Some(&buffer)).unwrap();
+        let swap = SwapFile::read(&path).unwrap();
+        assert_eq!(swap.pid, std::process::id());
+        assert_eq!(swap.cursor, (3, 1));
+        assert_eq!(swap.text, Some("one\ntwo 漢\n".as_bytes().to_vec()));
+
+        SwapFile::write(&path, (0, 2), None).unwrap();
+        let swap = SwapFile::read(&path).unwrap();
+        assert_eq!(swap.cursor, (0, 2));
+        assert_eq!(swap.text, None);
+        assert!(!path.with_extension("swp.tmp").exists());
+        fs::remove_file(&path).unwrap();
+        assert!(SwapFile::read(&path).is_none());
+    }
+
+    #[test]
+    fn malformed_swap_files() {
+        let path = swap_path("malformed");
+        for contents in [
+            "",
+            "vimacs-swap 1\n",
+            "vimacs-swap 1\npid 42\ncursor 1 2\n",
+            "vimacs-swap 1\npid 42\ncursor 1 2\nstate",
+            "vimacs-swap 2\npid 42\ncursor 1 2\nstate clean\n",
+            "vimacs-swap 1\npid -1\ncursor 1 2\nstate clean\n",
+            "vimacs-swap 1\npid 42\ncursor 1\nstate clean\n",
+            "vimacs-swap 1\npid 42\ncursor 1 x\nstate clean\n",
+            "vimacs-swap 1\npid 42\ncursor 1 2\nstate lost\n",
+        ]
+        .iter()
+        {
+            fs::write(&path, contents).unwrap();
+            assert!(SwapFile::read(&path).is_none(), "{:?}", contents);
+        }
+        // A dirty buffer that was empty
+        fs::write(&path, "vimacs-swap 1\npid 42\ncursor 1 2\nstate dirty").unwrap();
+        assert_eq!(SwapFile::read(&path).unwrap().text, Some(vec![]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn owner_alive() {
+        assert!(!swap_file(std::process::id()).is_owner_alive());
+        // Running, even if signalling it were not allowed
+        assert!(swap_file(1).is_owner_alive());
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!swap_file(pid).is_owner_alive());
+    }
+}
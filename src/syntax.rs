@@ -1,5 +1,760 @@
-struct Syntax {
-    file_type: String,
-    file_match: Vec<String>,
-    flags: u32;
+// *** SYNTAX ***
+// A syntax tells how to highlight one kind of file. Syntaxes are read from files
+// with one "property = values" line per property, see syntax/c.syntax for the
+// format. The built-in ones are compiled in from syntax/, and users can add
+// their own, or replace a built-in one of the same name, as NAME.syntax in the
+// syntax directory of the config directory. The properties are turned into
+// highlight rules, which syntax files can add to with rules of their own.
+use crate::config;
+use crate::rules::{Action, Grammar, Open, Rule};
+use std::cmp::Reverse;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const HIGHLIGHT_NUMBERS: u32 = 1;
+pub const HIGHLIGHT_STRINGS: u32 = 1 << 1;
+// Block comments can contain other block comments, as in Rust
+pub const NESTED_COMMENTS: u32 = 1 << 2;
+// A quote followed by a name is a lifetime rather than a char literal, as in Rust
+pub const LIFETIMES: u32 = 1 << 3;
+// #[...] and #![...] are attributes, as in Rust
+pub const ATTRIBUTES: u32 = 1 << 4;
+// A first line starting with #! names the interpreter
+pub const SHEBANG: u32 = 1 << 5;
+
+const FLAG_NAMES: [(&str, u32); 6] = [
+    ("numbers", HIGHLIGHT_NUMBERS),
+    ("strings", HIGHLIGHT_STRINGS),
+    ("nested_comments", NESTED_COMMENTS),
+    ("lifetimes", LIFETIMES),
+    ("attributes", ATTRIBUTES),
+    ("shebang", SHEBANG),
+];
+
+// Escapes in strings and char literals: \n, \x7f, \u{1F600} and \u00e9
+const ESCAPE_PATTERN: &str = r"\\(?:x[0-9a-fA-F]{2}|u\{[0-9a-fA-F]+\}|u[0-9a-fA-F]{4}|.)";
+
+const BUILTIN_SYNTAXES: [&str; 11] = [
+    include_str!("../syntax/c.syntax"),
+    include_str!("../syntax/rust.syntax"),
+    include_str!("../syntax/python.syntax"),
+    include_str!("../syntax/go.syntax"),
+    include_str!("../syntax/javascript.syntax"),
+    include_str!("../syntax/typescript.syntax"),
+    include_str!("../syntax/shell.syntax"),
+    include_str!("../syntax/toml.syntax"),
+    include_str!("../syntax/yaml.syntax"),
+    include_str!("../syntax/json.syntax"),
+    include_str!("../syntax/markdown.syntax"),
+];
+
+// Every group name a syntax has used, each kept for as long as the editor runs
+static GROUP_NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+// A highlight group, named like the theme group it is drawn in. Syntaxes can use
+// any name, e.g. "function" or "string.escape", which themes can then style.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Highlight(&'static str);
+
+impl Highlight {
+    pub const NORMAL: Highlight = Highlight("normal");
+    pub const NUMBER: Highlight = Highlight("number");
+    pub const MATCH: Highlight = Highlight("match");
+    pub const STRING: Highlight = Highlight("string");
+    pub const ESCAPE: Highlight = Highlight("string.escape");
+    pub const COMMENT: Highlight = Highlight("comment");
+    pub const KEYWORD1: Highlight = Highlight("keyword1");
+    pub const KEYWORD2: Highlight = Highlight("keyword2");
+    pub const LIFETIME: Highlight = Highlight("lifetime");
+    // Decorators, attributes and shebangs
+    pub const META: Highlight = Highlight("meta");
+
+    // The highlight for a group name, which is kept the first time it is seen
+    pub fn named(name: &str) -> Highlight {
+        let mut names = GROUP_NAMES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(known) = names.iter().find(|known| **known == name) {
+            return Highlight(known);
+        }
+        let name: &'static str = Box::leak(String::from(name).into_boxed_str());
+        names.push(name);
+        Highlight(name)
+    }
+
+    // Name of the theme group the highlight is drawn in
+    pub fn group(self) -> &'static str {
+        self.0
+    }
+}
+
+#[derive(Clone)]
+pub struct Syntax {
+    pub file_type: String,
+    extensions: Vec<String>,
+    file_globs: Vec<String>,
+    grammar: Grammar,
+}
+
+// The properties of a syntax file, before they are turned into rules
+#[derive(Default)]
+struct Properties {
+    single_line_comment_start: String,
+    multiline_comment_start: String,
+    multiline_comment_end: String,
+    // Characters that open and close strings, which end with the line
+    quotes: Vec<char>,
+    // Delimiters of strings that can span lines, each closed by itself, and
+    // whether the strings are raw
+    multiline_strings: Vec<(String, bool)>,
+    // Prefixes that make the string after them raw, like r in r"\d"
+    raw_prefixes: Vec<String>,
+    // Marks the name after it as a decorator, like @ in Python
+    decorator: String,
+    // Lines starting with this are headings, like # in Markdown
+    heading: String,
+    // Separates keys from values, like : in YAML
+    key_separator: Option<char>,
+    flags: u32,
+    keywords: Vec<(String, Highlight)>,
+    // Contexts declared by the file, with their highlight and whether they
+    // end with the line
+    contexts: Vec<(String, Highlight, bool)>,
+    rules: Vec<RuleLine>,
+}
+
+enum RuleAction {
+    Stay,
+    Push(String),
+    Pop,
+}
+
+// A rule given in a syntax file, kept until all contexts are known
+struct RuleLine {
+    // Line number, for errors
+    line: usize,
+    context: String,
+    highlight: Option<Highlight>,
+    pattern: String,
+    action: RuleAction,
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// A pattern matching any of words. Words are matched whole where they start or
+// end with a name character.
+fn words_pattern(words: &[&str]) -> String {
+    let mut words = words.to_vec();
+    words.sort_by_key(|word| Reverse(word.len()));
+    let words: Vec<String> = words
+        .iter()
+        .map(|word| {
+            let start = if word.starts_with(is_word) { r"\b" } else { "" };
+            let end = if word.ends_with(is_word) { r"\b" } else { "" };
+            format!("{}{}{}", start, regex::escape(word), end)
+        })
+        .collect();
+    format!("(?:{})", words.join("|"))
+}
+
+// Characters escaped for use inside a [...] class
+fn class_escape(chars: &[char]) -> String {
+    chars
+        .iter()
+        .map(|c| match c {
+            '\\' | ']' | '[' | '^' | '-' | '&' | '~' => format!("\\{}", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+impl Properties {
+    // The rules the properties stand for, ahead of the file's own rules
+    fn compile(&self) -> Result<Grammar, String> {
+        let mut grammar = Grammar::new();
+        let main = 0;
+        let flag = |bit: u32| self.flags & bit != 0;
+        let rule = |pattern: &str, highlight: Highlight, action: Action| {
+            Rule::new(pattern, Some(highlight), action).expect("Generated rule is invalid")
+        };
+        if flag(SHEBANG) {
+            let shebang = rule(r"^#!(?:[^\[].*)?$", Highlight::META, Action::Stay);
+            grammar.rule(main, shebang.on_first_line());
+        }
+        if !self.heading.is_empty() {
+            let pattern = format!("^{}.*", regex::escape(&self.heading));
+            grammar.rule(main, rule(&pattern, Highlight::KEYWORD1, Action::Stay));
+        }
+        if !self.single_line_comment_start.is_empty() {
+            let pattern = format!("{}.*", regex::escape(&self.single_line_comment_start));
+            grammar.rule(main, rule(&pattern, Highlight::COMMENT, Action::Stay));
+        }
+        if !self.multiline_comment_start.is_empty() {
+            let start = regex::escape(&self.multiline_comment_start);
+            let end = regex::escape(&self.multiline_comment_end);
+            let comment = grammar.context("block comment", Highlight::COMMENT, false);
+            grammar.rule(
+                main,
+                rule(&start, Highlight::COMMENT, Action::Push(comment)),
+            );
+            grammar.rule(comment, rule(&end, Highlight::COMMENT, Action::Pop));
+            if flag(NESTED_COMMENTS) {
+                grammar.rule(
+                    comment,
+                    rule(&start, Highlight::COMMENT, Action::Push(comment)),
+                );
+            }
+        }
+        if let Some(separator) = self.key_separator {
+            // Keys start a line, or follow '{' or ',' in inline tables, and items of
+            // YAML lists can be mappings. A name needs a space after the separator.
+            let before = r"(?:^|[{,])[ \t]*(?:-[ \t]+)*";
+            let separator = regex::escape(&separator.to_string());
+            for quote in self.quotes.iter() {
+                let q = regex::escape(&quote.to_string());
+                let pattern = format!(
+                    r"{}(?P<key>{q}(?:[^{c}\\]|\\.)*{q})[ \t]*{s}",
+                    before,
+                    q = q,
+                    c = class_escape(&[*quote]),
+                    s = separator
+                );
+                grammar.rule(main, rule(&pattern, Highlight::NORMAL, Action::Stay));
+            }
+            let mut excluded = self.quotes.clone();
+            excluded.extend(self.key_separator);
+            let pattern = format!(
+                r"{}(?P<key>[^\s{{}}\[\],{}]+)[ \t]*{}(?:\s|$)",
+                before,
+                class_escape(&excluded),
+                separator
+            );
+            grammar.rule(main, rule(&pattern, Highlight::NORMAL, Action::Stay));
+        }
+        if flag(HIGHLIGHT_STRINGS) {
+            // Longer delimiters come first, so """ wins over "
+            let mut delimiters: Vec<(String, bool, bool)> = self
+                .multiline_strings
+                .iter()
+                .map(|(delimiter, raw)| (delimiter.clone(), *raw, false))
+                .collect();
+            delimiters.sort_by_key(|(delimiter, _, _)| Reverse(delimiter.len()));
+            delimiters.extend(
+                self.quotes
+                    .iter()
+                    .map(|quote| (quote.to_string(), false, true)),
+            );
+            if !self.raw_prefixes.is_empty() {
+                // A raw prefix, then in Rust any number of '#', which the string
+                // has to be closed with as well, as in r#"..."#
+                let mut prefixes: Vec<&str> =
+                    self.raw_prefixes.iter().map(String::as_str).collect();
+                prefixes.sort_by_key(|prefix| Reverse(prefix.len()));
+                let prefixes: Vec<String> = prefixes.iter().map(|p| regex::escape(p)).collect();
+                for (delimiter, _, one_line) in delimiters.iter() {
+                    let d = regex::escape(delimiter);
+                    let name = format!("raw string {}", delimiter);
+                    let string = grammar.context(&name, Highlight::STRING, *one_line);
+                    let pattern = format!(r"\b(?:{})(#*){}", prefixes.join("|"), d);
+                    grammar.rule(
+                        main,
+                        rule(&pattern, Highlight::STRING, Action::Push(string)),
+                    );
+                    let close = format!(r"{}\1", d);
+                    grammar.rule(string, rule(&close, Highlight::STRING, Action::Pop));
+                }
+            }
+            for (delimiter, raw, one_line) in delimiters.iter() {
+                let d = regex::escape(delimiter);
+                let name = format!("string {}", delimiter);
+                let string = grammar.context(&name, Highlight::STRING, *one_line);
+                grammar.rule(main, rule(&d, Highlight::STRING, Action::Push(string)));
+                if !raw {
+                    grammar.rule(
+                        string,
+                        rule(ESCAPE_PATTERN, Highlight::ESCAPE, Action::Stay),
+                    );
+                }
+                grammar.rule(string, rule(&d, Highlight::STRING, Action::Pop));
+            }
+        }
+        if flag(LIFETIMES) {
+            let escaped = format!("'(?P<string.escape>{})'", ESCAPE_PATTERN);
+            grammar.rule(main, rule(&escaped, Highlight::STRING, Action::Stay));
+            grammar.rule(main, rule(r"'[^\\']'", Highlight::STRING, Action::Stay));
+            grammar.rule(main, rule(r"'[\w]+", Highlight::LIFETIME, Action::Stay));
+        }
+        if flag(ATTRIBUTES) {
+            // Up to the matching bracket
+            let attribute = grammar.context("attribute brackets", Highlight::META, false);
+            grammar.rule(
+                main,
+                rule(r"#!?\[", Highlight::META, Action::Push(attribute)),
+            );
+            grammar.rule(
+                attribute,
+                rule(r"\[", Highlight::META, Action::Push(attribute)),
+            );
+            grammar.rule(attribute, rule(r"\]", Highlight::META, Action::Pop));
+        }
+        if !self.decorator.is_empty() {
+            let pattern = format!(r"{}\w[\w.]*", regex::escape(&self.decorator));
+            grammar.rule(main, rule(&pattern, Highlight::META, Action::Stay));
+        }
+        if flag(HIGHLIGHT_NUMBERS) {
+            // 0x1f, 0b101, 0o17, 1_000, 2.5 and 1e-9, followed by a type suffix
+            // like u8 or f64
+            let pattern = concat!(
+                r"\b(?:0[xX][0-9a-fA-F_]+|0[bB][01_]+|0[oO][0-7_]+",
+                r"|[0-9][0-9_]*(?:\.[0-9][0-9_]*)?(?:[eE][+-]?[0-9][0-9_]*)?)\w*"
+            );
+            grammar.rule(main, rule(pattern, Highlight::NUMBER, Action::Stay));
+        }
+        for highlight in [Highlight::KEYWORD1, Highlight::KEYWORD2].iter() {
+            let words: Vec<&str> = self
+                .keywords
+                .iter()
+                .filter(|(_, h)| h == highlight)
+                .map(|(word, _)| word.as_str())
+                .collect();
+            if !words.is_empty() {
+                grammar.rule(main, rule(&words_pattern(&words), *highlight, Action::Stay));
+            }
+        }
+        for (name, highlight, one_line) in self.contexts.iter() {
+            if grammar.find(name).is_some() {
+                return Err(format!("context {} is declared twice", name));
+            }
+            grammar.context(name, *highlight, *one_line);
+        }
+        for line in self.rules.iter() {
+            let error = |msg: String| format!("line {}: {}", line.line, msg);
+            let context = grammar
+                .find(&line.context)
+                .expect("Declared before its rules");
+            let action = match &line.action {
+                RuleAction::Stay => Action::Stay,
+                RuleAction::Pop => Action::Pop,
+                RuleAction::Push(name) => match grammar.find(name) {
+                    Some(target) => Action::Push(target),
+                    None => return Err(error(format!("no context named {}", name))),
+                },
+            };
+            let rule = Rule::new(&line.pattern, line.highlight, action).map_err(error)?;
+            grammar.rule(context, rule);
+        }
+        Ok(grammar)
+    }
+}
+
+// A group name, or '-' for the highlight of the context
+fn parse_group(group: &str) -> Option<Highlight> {
+    match group {
+        "-" => None,
+        group => Some(Highlight::named(group)),
+    }
+}
+
+impl Syntax {
+    pub fn new() -> Syntax {
+        Syntax {
+            file_type: String::new(),
+            extensions: vec![],
+            file_globs: vec![],
+            grammar: Grammar::new(),
+        }
+    }
+
+    // Whether the syntax is the one for the file at path
+    pub fn matches(&self, path: &str) -> bool {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path);
+        self.extensions
+            .iter()
+            .any(|ext| name.ends_with(ext.as_str()))
+            || self.file_globs.iter().any(|glob| glob_match(glob, name))
+    }
+
+    // Highlights one line of rendered text starting in what the line before left
+    // open, and returns the highlight of each char and what the line leaves open
+    pub fn highlight_line(&self, line: &str, start: &Open) -> (Vec<Highlight>, Open) {
+        self.grammar.highlight(line, start)
+    }
+
+    // Whether a comment or string can span lines, so that what each line starts
+    // in depends on the lines before it
+    pub fn spans_lines(&self) -> bool {
+        self.grammar.spans_lines()
+    }
+
+    fn parse(text: &str) -> Result<Syntax, String> {
+        let mut syntax = Syntax::new();
+        let mut properties = Properties::default();
+        // The context rules are added to, changed by context lines
+        let mut context = String::from("main");
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: String| format!("line {}: {}", i + 1, msg);
+            let (property, text) = line
+                .split_once('=')
+                .ok_or_else(|| error(String::from("expected 'property = values'")))?;
+            let values: Vec<String> = text.split_whitespace().map(String::from).collect();
+            let single = || match values.as_slice() {
+                [value] => Ok(value.clone()),
+                _ => Err(error(format!("{} takes one value", property.trim()))),
+            };
+            // Rule properties take a name after the property, and keep the spaces
+            // of their pattern
+            let mut words = property.split_whitespace();
+            let (name, argument) = (words.next().unwrap_or(""), words.next());
+            let rule = |action: RuleAction| {
+                let (group, pattern) = text
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| error(String::from("expected 'group pattern'")))?;
+                Ok::<RuleLine, String>(RuleLine {
+                    line: i + 1,
+                    context: context.clone(),
+                    highlight: parse_group(group),
+                    pattern: String::from(pattern.trim()),
+                    action,
+                })
+            };
+            match (name, argument) {
+                ("rule", None) => properties.rules.push(rule(RuleAction::Stay)?),
+                ("pop", None) => properties.rules.push(rule(RuleAction::Pop)?),
+                ("push", Some(target)) => properties
+                    .rules
+                    .push(rule(RuleAction::Push(String::from(target)))?),
+                ("context", Some(name)) => {
+                    let (highlight, one_line) = match values.as_slice() {
+                        [group] => (parse_group(group), false),
+                        [group, one_line] if one_line == "one_line" => (parse_group(group), true),
+                        _ => return Err(error(String::from("expected 'group [one_line]'"))),
+                    };
+                    if name == "main" || properties.contexts.iter().any(|(c, _, _)| c == name) {
+                        return Err(error(format!("context {} is declared twice", name)));
+                    }
+                    let highlight = highlight.unwrap_or(Highlight::NORMAL);
+                    properties
+                        .contexts
+                        .push((String::from(name), highlight, one_line));
+                    context = String::from(name);
+                }
+                ("name", None) => syntax.file_type = single()?,
+                ("extensions", None) => syntax.extensions.extend(values),
+                ("files", None) => syntax.file_globs.extend(values),
+                ("comment", None) => properties.single_line_comment_start = single()?,
+                ("block_comment", None) => match values.as_slice() {
+                    [start, end] => {
+                        properties.multiline_comment_start = start.clone();
+                        properties.multiline_comment_end = end.clone();
+                    }
+                    _ => {
+                        return Err(error(String::from(
+                            "block_comment takes a start and an end",
+                        )))
+                    }
+                },
+                ("strings", None) => {
+                    for quote in values.iter() {
+                        let mut chars = quote.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => properties.quotes.push(c),
+                            _ => {
+                                return Err(error(format!("'{}' is not a single character", quote)))
+                            }
+                        }
+                    }
+                }
+                ("multiline_strings", None) => properties
+                    .multiline_strings
+                    .extend(values.into_iter().map(|delimiter| (delimiter, false))),
+                ("raw_strings", None) => properties
+                    .multiline_strings
+                    .extend(values.into_iter().map(|delimiter| (delimiter, true))),
+                ("raw_prefixes", None) => properties.raw_prefixes.extend(values),
+                ("decorator", None) => properties.decorator = single()?,
+                ("heading", None) => properties.heading = single()?,
+                ("keys", None) => {
+                    let separator = single()?;
+                    let mut chars = separator.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => properties.key_separator = Some(c),
+                        _ => {
+                            return Err(error(format!("'{}' is not a single character", separator)))
+                        }
+                    }
+                }
+                ("flags", None) => {
+                    for flag in values.iter() {
+                        match FLAG_NAMES.iter().find(|(name, _)| name == flag) {
+                            Some((_, bit)) => properties.flags |= bit,
+                            None => return Err(error(format!("unknown flag '{}'", flag))),
+                        }
+                    }
+                }
+                ("keyword1", None) => properties
+                    .keywords
+                    .extend(values.into_iter().map(|word| (word, Highlight::KEYWORD1))),
+                ("keyword2", None) => properties
+                    .keywords
+                    .extend(values.into_iter().map(|word| (word, Highlight::KEYWORD2))),
+                _ => return Err(error(format!("unknown property '{}'", property.trim()))),
+            }
+        }
+        if syntax.file_type.is_empty() {
+            return Err(String::from("the syntax has no name"));
+        }
+        syntax.grammar = properties.compile()?;
+        Ok(syntax)
+    }
+
+    // The user's syntaxes followed by the built-in ones they don't replace, and
+    // an error message for each file that could not be loaded
+    pub fn load_all() -> (Vec<Syntax>, Vec<String>) {
+        let mut syntaxes = vec![];
+        let mut errors = vec![];
+        let entries = syntax_dir().and_then(|dir| fs::read_dir(dir).ok());
+        let mut paths: Vec<PathBuf> = entries
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "syntax"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            let loaded = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| Syntax::parse(&text));
+            match loaded {
+                Ok(syntax) => syntaxes.push(syntax),
+                Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+        for builtin in Syntax::builtin() {
+            if !syntaxes
+                .iter()
+                .any(|s: &Syntax| s.file_type == builtin.file_type)
+            {
+                syntaxes.push(builtin);
+            }
+        }
+        (syntaxes, errors)
+    }
+
+    // The syntaxes compiled in from syntax/
+    pub fn builtin() -> Vec<Syntax> {
+        BUILTIN_SYNTAXES
+            .iter()
+            .map(|text| Syntax::parse(text).expect("Built-in syntax is invalid"))
+            .collect()
+    }
+}
+
+fn syntax_dir() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("syntax"))
+}
+
+// Matches name against a glob where '*' stands for any run of characters and
+// '?' for any single one
+fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where the last '*' was seen, and the part of name it has matched so far
+    let mut star = None;
+    let (mut g, mut n) = (0, 0);
+    while n < name.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == name[n]) {
+            g += 1;
+            n += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g, n));
+            g += 1;
+        } else if let Some((star_g, star_n)) = star {
+            // Let the '*' take one more character
+            star = Some((star_g, star_n + 1));
+            g = star_g + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One letter for each highlight in the .hl files, which hold a line of
+    // letters under each line of a fixture
+    fn letter(highlight: &Highlight) -> char {
+        match highlight.group() {
+            "normal" => '.',
+            "number" => 'n',
+            "match" => 'x',
+            "string" => 's',
+            "string.escape" => 'e',
+            "comment" => 'c',
+            "keyword1" => 'k',
+            "keyword2" => 't',
+            "lifetime" => 'l',
+            "meta" => 'm',
+            "key" => 'y',
+            "function" => 'f',
+            "type" => 'T',
+            "macro" => 'M',
+            "operator" => 'o',
+            "variable" => 'v',
+            group => panic!("no letter for {}", group),
+        }
+    }
+
+    // Highlights syntax/fixtures/name with the built-in syntax for it and
+    // compares the result with name.hl. Trailing normal text is left out.
+    fn check(name: &str) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("syntax/fixtures");
+        let source = fs::read_to_string(dir.join(name)).unwrap();
+        let expected = fs::read_to_string(dir.join(format!("{}.hl", name))).unwrap();
+        let syntax = Syntax::builtin()
+            .into_iter()
+            .find(|syntax| syntax.matches(name))
+            .unwrap();
+        let mut open = Open::top();
+        let mut expected = expected.lines();
+        for (i, line) in source.lines().enumerate() {
+            let (highlight, end) = syntax.highlight_line(line, &open);
+            open = end;
+            let actual: String = highlight.iter().map(letter).collect();
+            assert_eq!(
+                actual.trim_end_matches('.'),
+                expected.next().unwrap_or("").trim_end_matches('.'),
+                "{} line {}: {}",
+                name,
+                i + 1,
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn c() {
+        check("sample.c");
+    }
+
+    #[test]
+    fn spans_lines() {
+        let spans = |name: &str| {
+            Syntax::builtin()
+                .into_iter()
+                .find(|syntax| syntax.matches(name))
+                .unwrap()
+                .spans_lines()
+        };
+        assert!(spans("a.c"));
+        assert!(spans("a.py"));
+        assert!(spans("a.sh"));
+        assert!(spans("a.json"));
+        // Strings and comments end with the line
+        assert!(!spans("a.yaml"));
+        assert!(!Syntax::new().spans_lines());
+    }
+
+    #[test]
+    fn rust() {
+        check("sample.rs");
+    }
+
+    #[test]
+    fn python() {
+        check("sample.py");
+    }
+
+    #[test]
+    fn go() {
+        check("sample.go");
+    }
+
+    #[test]
+    fn javascript() {
+        check("sample.js");
+    }
+
+    #[test]
+    fn typescript() {
+        check("sample.ts");
+    }
+
+    #[test]
+    fn shell() {
+        check("sample.sh");
+    }
+
+    #[test]
+    fn toml() {
+        check("sample.toml");
+    }
+
+    #[test]
+    fn yaml() {
+        check("sample.yaml");
+    }
+
+    #[test]
+    fn json() {
+        check("sample.json");
+    }
+
+    #[test]
+    fn markdown() {
+        check("sample.md");
+    }
+
+    #[test]
+    fn rules() {
+        let syntax = Syntax::parse(concat!(
+            "name = heredocs\n",
+            "rule = keyword1 \\bend\\b\n",
+            "rule = - (?P<function>\\w+)\\(\n",
+            "push heredoc = string <<(\\w+)\n",
+            "context heredoc = string\n",
+            "pop = string ^\\1$\n",
+            "rule = string.escape \\$\\w+\n",
+        ))
+        .unwrap();
+        let lines = ["f(x) <<EOF end", "$a end", "EOFX", "EOF", "end"];
+        let expected = ["f....sssssssss", "eessss", "ssss", "sss", "kkk"];
+        let mut open = Open::top();
+        for (line, expected) in lines.iter().zip(expected.iter()) {
+            let (highlight, end) = syntax.highlight_line(line, &open);
+            open = end;
+            let actual: String = highlight.iter().map(letter).collect();
+            assert_eq!(actual, *expected, "{}", line);
+        }
+        let error = |text: &str| Syntax::parse(&format!("name = broken\n{}", text)).err();
+        assert_eq!(
+            error("push nowhere = - x"),
+            Some(String::from("line 2: no context named nowhere"))
+        );
+        assert!(error("rule = string (").unwrap().starts_with("line 2: "));
+        assert!(error("rule = string").is_some());
+        assert!(error("context a = -\ncontext a = -").is_some());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*.mk", "rules.mk"));
+        assert!(glob_match("Makefile", "Makefile"));
+        assert!(glob_match("a*b?c", "axxbyc"));
+        assert!(!glob_match("*.mk", "rules.mkx"));
+        assert!(!glob_match("a?", "a"));
+    }
 }
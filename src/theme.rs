@@ -0,0 +1,386 @@
+// *** THEMES ***
+// A theme gives the style of each highlight group. Themes are read from files
+// with one "group = style" line per group, see themes/default.theme for the
+// format. The built-in themes are compiled in from themes/, and users can add
+// their own as NAME.theme in the themes directory of the config directory.
+use crate::config;
+use crate::screen::{Color, Style};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const BUILTIN_THEMES: [(&str, &str); 2] = [
+    ("default", include_str!("../themes/default.theme")),
+    ("dusk", include_str!("../themes/dusk.theme")),
+];
+
+const COLOR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+// Approximate RGB values of the 16 standard colors, as xterm shows them
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+// Levels of each channel in the 6x6x6 color cube of the 256 color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// How many colors the terminal can show
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum ColorDepth {
+    Ansi16,
+    Indexed256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    // Terminals that support 24-bit color say so in COLORTERM, and 256 color
+    // terminals usually have a TERM like xterm-256color
+    pub fn detect() -> ColorDepth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Indexed256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+
+    // The nearest color the terminal can show
+    fn fit(self, color: Color) -> Color {
+        match (color, self) {
+            (Color::Rgb(r, g, b), ColorDepth::Indexed256) => {
+                Color::Indexed(nearest_indexed(r, g, b))
+            }
+            (Color::Rgb(r, g, b), ColorDepth::Ansi16) => Color::Ansi(nearest_ansi(r, g, b)),
+            (Color::Indexed(n), ColorDepth::Ansi16) if n < 16 => Color::Ansi(n),
+            (Color::Indexed(n), ColorDepth::Ansi16) => {
+                let (r, g, b) = indexed_rgb(n);
+                Color::Ansi(nearest_ansi(r, g, b))
+            }
+            _ => color,
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+fn nearest_ansi(r: u8, g: u8, b: u8) -> u8 {
+    (0..16)
+        .min_by_key(|n| distance(ANSI_RGB[*n], (r, g, b)))
+        .unwrap_or(0) as u8
+}
+
+fn indexed_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_RGB[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (n - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+// The closest color of the cube or the gray ramp of the 256 color palette
+fn nearest_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| {
+        (0..6)
+            .min_by_key(|i| (CUBE_LEVELS[*i] as i32 - c as i32).abs())
+            .unwrap_or(0) as u8
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23) as u8;
+    if distance(indexed_rgb(gray), (r, g, b)) < distance(indexed_rgb(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn parse_color(text: &str) -> Option<Color> {
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    if let Ok(n) = text.parse::<u8>() {
+        return Some(Color::Indexed(n));
+    }
+    if text == "default" {
+        return Some(Color::Default);
+    }
+    let (name, offset) = match text.strip_prefix("bright-") {
+        Some(name) => (name, 8),
+        None => (text, 0),
+    };
+    let n = COLOR_NAMES.iter().position(|color| *color == name)?;
+    Some(Color::Ansi(n as u8 + offset))
+}
+
+fn parse_style(text: &str, depth: ColorDepth) -> Result<Style, String> {
+    let mut style = Style::DEFAULT;
+    for word in text.split_whitespace() {
+        let color = |value: &str| {
+            parse_color(value)
+                .map(|color| depth.fit(color))
+                .ok_or(format!("unknown color '{}'", value))
+        };
+        match word {
+            "bold" => style.bold = true,
+            "italic" => style.italic = true,
+            "underline" => style.underline = true,
+            "reverse" => style.reverse = true,
+            _ => {
+                if let Some(value) = word.strip_prefix("fg=") {
+                    style.fg = color(value)?;
+                } else if let Some(value) = word.strip_prefix("bg=") {
+                    style.bg = color(value)?;
+                } else {
+                    return Err(format!("unknown attribute '{}'", word));
+                }
+            }
+        }
+    }
+    Ok(style)
+}
+
+pub struct Theme {
+    pub name: String,
+    styles: HashMap<String, Style>,
+}
+
+impl Theme {
+    // The theme the editor starts with: the one named by VIMACS_THEME, or the
+    // default theme if that is not set or can't be loaded
+    pub fn initial(depth: ColorDepth) -> Theme {
+        env::var("VIMACS_THEME")
+            .ok()
+            .and_then(|name| Theme::load(&name, depth).ok())
+            .unwrap_or_else(|| Theme::builtin("default", depth))
+    }
+
+    // Loads a theme by name, looking at the user's themes before the built-in ones
+    pub fn load(name: &str, depth: ColorDepth) -> Result<Theme, String> {
+        if let Some(path) = themes_dir().map(|dir| dir.join(format!("{}.theme", name))) {
+            if path.exists() {
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                return Theme::parse(name, &text, depth)
+                    .map_err(|e| format!("{}: {}", path.display(), e));
+            }
+        }
+        match BUILTIN_THEMES.iter().find(|(builtin, _)| *builtin == name) {
+            Some(_) => Ok(Theme::builtin(name, depth)),
+            None => Err(format!("No theme named {}", name)),
+        }
+    }
+
+    fn builtin(name: &str, depth: ColorDepth) -> Theme {
+        let (_, text) = BUILTIN_THEMES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .unwrap_or(&BUILTIN_THEMES[0]);
+        Theme::parse(name, text, depth).expect("Built-in theme is invalid")
+    }
+
+    // Themes start out as the default theme, and the file overrides its groups
+    fn parse(name: &str, text: &str, depth: ColorDepth) -> Result<Theme, String> {
+        let mut styles = HashMap::new();
+        if name != "default" {
+            styles = Theme::builtin("default", depth).styles;
+        }
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (group, style) = line
+                .split_once('=')
+                .ok_or(format!("line {}: expected 'group = style'", i + 1))?;
+            let style = parse_style(style, depth).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            styles.insert(String::from(group.trim()), style);
+        }
+        Ok(Theme {
+            name: String::from(name),
+            styles,
+        })
+    }
+
+    // Names of the built-in themes and those in the user's themes directory
+    pub fn available() -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN_THEMES
+            .iter()
+            .map(|(name, _)| String::from(*name))
+            .collect();
+        let entries = themes_dir().and_then(|dir| fs::read_dir(dir).ok());
+        for entry in entries.into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "theme") {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    if !names.iter().any(|known| known == name) {
+                        names.push(String::from(name));
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    // The style of a highlight group such as "string" or "ui.statusbar". A group
+    // the theme doesn't define takes the style of its parent, "ui.linenumber"
+    // for "ui.linenumber.current", and every group is drawn over normal.
+    pub fn style(&self, group: &str) -> Style {
+        self.over(self.lookup("normal"), group)
+    }
+
+    // Draws a group on top of an existing style, e.g. the selection over the
+    // colors of the text
+    pub fn over(&self, style: Style, group: &str) -> Style {
+        style.overlay(self.lookup(group))
+    }
+
+    fn lookup(&self, group: &str) -> Style {
+        let mut group = group;
+        loop {
+            if let Some(style) = self.styles.get(group) {
+                return *style;
+            }
+            match group.rfind('.') {
+                Some(dot) => group = &group[..dot],
+                None => return Style::DEFAULT,
+            }
+        }
+    }
+}
+
+fn themes_dir() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("themes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#1e90Ff"), Some(Color::Rgb(0x1e, 0x90, 0xff)));
+        assert_eq!(parse_color("#1e90f"), None);
+        assert_eq!(parse_color("#1e90fg"), None);
+        assert_eq!(parse_color("#1é90f"), None);
+        assert_eq!(parse_color("208"), Some(Color::Indexed(208)));
+        assert_eq!(parse_color("256"), None);
+        assert_eq!(parse_color("default"), Some(Color::Default));
+        assert_eq!(parse_color("blue"), Some(Color::Ansi(4)));
+        assert_eq!(parse_color("bright-white"), Some(Color::Ansi(15)));
+        assert_eq!(parse_color("bright-default"), None);
+        assert_eq!(parse_color("purple"), None);
+    }
+
+    #[test]
+    fn nearest_colors() {
+        // Exact colors of the cube and the gray ramp
+        assert_eq!(nearest_indexed(0, 0, 0), 16);
+        assert_eq!(nearest_indexed(255, 0, 0), 196);
+        assert_eq!(nearest_indexed(95, 135, 175), 67);
+        assert_eq!(nearest_indexed(128, 128, 128), 244);
+        assert_eq!(nearest_indexed(238, 238, 238), 255);
+        // Grays in between the cube's levels go to the ramp, colors to the cube
+        assert_eq!(nearest_indexed(50, 50, 50), 236);
+        assert_eq!(nearest_indexed(200, 60, 10), 166);
+        for n in 16..=255 {
+            let (r, g, b) = indexed_rgb(n);
+            assert_eq!(indexed_rgb(nearest_indexed(r, g, b)), (r, g, b));
+        }
+
+        assert_eq!(nearest_ansi(250, 10, 10), 9);
+        assert_eq!(ColorDepth::Ansi16.fit(Color::Indexed(4)), Color::Ansi(4));
+        assert_eq!(ColorDepth::Ansi16.fit(Color::Indexed(231)), Color::Ansi(15));
+        assert_eq!(
+            ColorDepth::TrueColor.fit(Color::Rgb(1, 2, 3)),
+            Color::Rgb(1, 2, 3)
+        );
+    }
+
+    #[test]
+    fn styles() {
+        let style = parse_style(
+            "fg=#ff0000 bg=default bold underline",
+            ColorDepth::Indexed256,
+        );
+        assert_eq!(
+            style,
+            Ok(Style {
+                fg: Color::Indexed(196),
+                bold: true,
+                underline: true,
+                ..Style::DEFAULT
+            })
+        );
+        assert!(parse_style("fg=nope", ColorDepth::TrueColor).is_err());
+        assert!(parse_style("blink", ColorDepth::TrueColor).is_err());
+
+        let theme = Theme::parse(
+            "test",
+            "test = fg=red\ntest.group = bold\n",
+            ColorDepth::Ansi16,
+        )
+        .unwrap();
+        // Groups the theme doesn't define fall back to their parent and the default theme
+        assert!(theme.style("test.group.current").bold);
+        assert_eq!(theme.style("test.other").fg, Color::Ansi(1));
+        assert!(!theme.style("test.other").bold);
+        assert_eq!(
+            theme.lookup("comment"),
+            Theme::builtin("default", ColorDepth::Ansi16).lookup("comment")
+        );
+        assert!(Theme::parse("test", "ui fg=red", ColorDepth::Ansi16).is_err());
+    }
+
+    #[test]
+    fn builtin_themes_parse() {
+        for (name, text) in BUILTIN_THEMES.iter() {
+            for depth in [
+                ColorDepth::Ansi16,
+                ColorDepth::Indexed256,
+                ColorDepth::TrueColor,
+            ]
+            .iter()
+            {
+                assert!(Theme::parse(name, text, *depth).is_ok(), "{}", name);
+            }
+        }
+    }
+}
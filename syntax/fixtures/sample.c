@@ -0,0 +1,8 @@
+/* header
+   comment */
+#include <stdio.h>
+int main(void) {
+    char c = '\'';
+    printf("%d\n", 42); // print
+    return 0;
+}
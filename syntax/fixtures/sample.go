@@ -0,0 +1,14 @@
+package main
+
+import "fmt"
+
+/* block
+   comment */
+func main() {
+    var r rune = 'x'
+    path := `C:\raw
+still raw`
+    n := 0xFF + 1_000 + 0.5
+    fmt.Println("hi\n", r, path, n, nil) // call
+    os.Exit(len(path) % 2)
+}
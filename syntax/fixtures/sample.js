@@ -0,0 +1,9 @@
+#!/usr/bin/env node
+@decorator
+class A extends B {
+  static x = 0b11n;
+}
+const s = `template
+${x} done`;
+let y = 'a' + "b" /* c */ + null;
+console.log(new Date(), parse(s) !== y);
@@ -0,0 +1,19 @@
+#!/usr/bin/env python3
+import re
+
+
+@dataclass
+class Point:
+    """A point.
+
+    # not a comment
+    """
+
+    @property.setter
+    def x(self):
+        pattern = r'\d+'
+        raw = rb"\n" + b'\x00'
+        return 0x1f + 1_000 + 3.14 + 1e10 + 2j  # sum
+x = '''a
+b''' @ y
+print(Point(1, 2) == x)
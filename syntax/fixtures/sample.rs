@@ -0,0 +1,20 @@
+#!/usr/bin/env run-cargo-script
+#![allow(dead_code)]
+/* block /* nested */ still comment */
+#[derive(Debug)]
+struct Wrapper<'a> {
+    text: &'a str,
+}
+
+fn main() {
+    let c = 'x';
+    let newline = '\n';
+    let raw = r#"C:\path "quoted""#;
+    let bytes = br"\d+";
+    let n = 0x1F + 0b1010 + 0o17 + 1_000u32;
+    let f = 2.5e-3f64;
+    let s = "multi
+line";
+    // done
+    println!("{}", Wrapper::new(c).text.len() * MAX);
+}
@@ -0,0 +1,7 @@
+#!/bin/bash
+# comment
+if [ -n "$HOME" ]; then
+  echo 'single $quoted' "multi
+line" 42
+fi
+greet() { echo $1 ${name:-x} | tr a-z A-Z > /dev/null && exit; }
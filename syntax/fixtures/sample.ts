@@ -0,0 +1,4 @@
+interface Point { x: number; y: string }
+type Id = string | undefined;
+@Component({ selector: 'app' })
+export const p: Point = { x: 1.5, y: `id` };